@@ -0,0 +1,45 @@
+#!/usr/bin/env bash
+# Times each benchmark with a release build of rox, best of $RUNS runs (5
+# by default). Given a git revision, builds that too and compares, e.g.
+#
+#   bench/run.sh HEAD~1
+set -euo pipefail
+cd "$(dirname "$0")/.."
+runs=${RUNS:-5}
+
+cargo build --release --quiet
+current=target/release/rox
+
+baseline=""
+if [ $# -gt 0 ]; then
+  source_dir=target/bench/source
+  rm -rf "$source_dir"
+  mkdir -p "$source_dir"
+  git archive "$1" | tar -x -C "$source_dir"
+  cargo build --release --quiet --manifest-path "$source_dir/Cargo.toml" --target-dir target/bench/target
+  baseline=target/bench/target/release/rox
+fi
+
+# Best wall-clock time of several runs, in seconds
+best() {
+  local TIMEFORMAT=%R
+  for _ in $(seq "$runs"); do
+    { time "$1" "$2" > /dev/null; } 2>&1
+  done | sort -n | head -1
+}
+
+if [ -n "$baseline" ]; then
+  printf '%-16s %10s %10s %8s\n' benchmark "$1" current speedup
+else
+  printf '%-16s %10s\n' benchmark current
+fi
+for script in bench/*.lox; do
+  after=$(best "$current" "$script")
+  if [ -n "$baseline" ]; then
+    before=$(best "$baseline" "$script")
+    speedup=$(awk -v a="$before" -v b="$after" 'BEGIN { printf "%.2fx", a / b }')
+    printf '%-16s %9ss %9ss %8s\n' "$(basename "$script")" "$before" "$after" "$speedup"
+  else
+    printf '%-16s %9ss\n' "$(basename "$script")" "$after"
+  fi
+done
@@ -0,0 +1,17 @@
+// Starts `rox debug --dap` for launch configurations of type "rox"
+const vscode = require("vscode");
+
+function activate(context) {
+    context.subscriptions.push(
+        vscode.debug.registerDebugAdapterDescriptorFactory("rox", {
+            createDebugAdapterDescriptor() {
+                const rox = vscode.workspace.getConfiguration("rox").get("path", "rox");
+                return new vscode.DebugAdapterExecutable(rox, ["debug", "--dap"]);
+            },
+        })
+    );
+}
+
+function deactivate() {}
+
+module.exports = { activate, deactivate };
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+
+use crate::parser::{Expr, Parser, Stmt};
+use crate::scanner::Scanner;
+use crate::symbol::SymbolMap;
+use crate::token::{Span, Token};
+use crate::token_type::TokenType;
+
+// Static picture of a program for editor tooling: every declared name, where
+// it's declared and where it's used. Works on code with parse errors by
+// using whatever statements did parse.
+pub struct Analysis {
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    // How the declaration reads, like "fun add(a, b)"
+    pub detail: String,
+    pub declaration: Span,
+    pub references: Vec<Span>,
+    // Declared at the top level of the program
+    pub global: bool,
+    // For methods, the index of their class in symbols
+    pub container: Option<usize>,
+    // Byte range of the source where the name can be used
+    pub visible: (usize, usize),
+}
+
+impl SymbolKind {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SymbolKind::Variable => "variable",
+            SymbolKind::Parameter => "parameter",
+            SymbolKind::Function => "function",
+            SymbolKind::Class => "class",
+            SymbolKind::Method => "method",
+        }
+    }
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Analysis {
+        let tokens = Scanner::new(source).scan_tokens();
+        let braces = brace_pairs(&tokens);
+        let (statements, _) = Parser::new(tokens).parse_partial();
+
+        let mut analyzer = Analyzer {
+            symbols: Vec::new(),
+            scopes: Vec::new(),
+            globals: SymbolMap::default(),
+            unresolved: Vec::new(),
+            properties: Vec::new(),
+            classes: Vec::new(),
+            superclasses: HashMap::new(),
+            braces,
+            source_len: source.len(),
+        };
+        analyzer.statements(&statements);
+        analyzer.finish()
+    }
+
+    // The symbol declared or used at a byte offset
+    pub fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        let contains = |span: &Span| span.start <= offset && offset <= span.end;
+        self.symbols
+            .iter()
+            .find(|symbol| contains(&symbol.declaration) || symbol.references.iter().any(contains))
+    }
+
+    // Names that can be used at a byte offset, innermost first. Methods are
+    // left out since they're only reached through an instance.
+    pub fn visible_at(&self, offset: usize) -> Vec<&Symbol> {
+        let mut visible = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind != SymbolKind::Method)
+            .filter(|symbol| symbol.visible.0 <= offset && offset <= symbol.visible.1)
+            .collect::<Vec<&Symbol>>();
+        visible.sort_by_key(|symbol| std::cmp::Reverse(symbol.visible.0));
+        let mut seen = Vec::new();
+        visible.retain(|symbol| {
+            let new = !seen.contains(&symbol.name);
+            seen.push(symbol.name.clone());
+            new
+        });
+        visible
+    }
+
+    pub fn methods(&self, class: usize) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(move |symbol| symbol.container == Some(class))
+    }
+}
+
+struct Analyzer {
+    symbols: Vec<Symbol>,
+    // Local scopes, mapping names to symbols, as the resolver keeps them
+    scopes: Vec<SymbolMap<usize>>,
+    globals: SymbolMap<usize>,
+    // Uses of names not declared locally. Globals can be declared after the
+    // functions that use them, so these are matched up at the end.
+    unresolved: Vec<Token>,
+    // Names used after a dot, with the class to look them up in when the
+    // object is 'this' or 'super'. Matched to methods at the end.
+    properties: Vec<(Token, Option<usize>)>,
+    // Classes whose methods are being analysed, innermost last
+    classes: Vec<usize>,
+    superclasses: HashMap<usize, usize>,
+    // Byte offsets of matching '{' and '}'
+    braces: Vec<(usize, usize)>,
+    source_len: usize,
+}
+
+impl Analyzer {
+    fn finish(mut self) -> Analysis {
+        for name in std::mem::take(&mut self.unresolved) {
+            if let Some(&id) = self.globals.get(&name.lexeme.symbol()) {
+                self.symbols[id].references.push(name.span);
+            }
+        }
+        for (name, class) in std::mem::take(&mut self.properties) {
+            if let Some(id) = self.method(&name.lexeme, class) {
+                self.symbols[id].references.push(name.span);
+            }
+        }
+        for symbol in &mut self.symbols {
+            // Desugared operators like "a += 1" reuse the name token
+            symbol.references.sort_by_key(|span| span.start);
+            symbol.references.dedup_by_key(|span| span.start);
+        }
+        Analysis {
+            symbols: self.symbols,
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        for stmt in statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.scopes.push(SymbolMap::default());
+                self.statements(statements);
+                self.scopes.pop();
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let detail = match superclass.as_deref() {
+                    Some(Expr::Variable(superclass, _)) => {
+                        format!("class {} < {}", name.lexeme, superclass.lexeme)
+                    }
+                    _ => format!("class {}", name.lexeme),
+                };
+                let class = self.declare(name, SymbolKind::Class, detail);
+                if let Some(superclass) = superclass {
+                    self.expression(superclass);
+                    if let Expr::Variable(superclass, _) = superclass.as_ref() {
+                        match self.lookup(superclass) {
+                            Some(id) if id != class => {
+                                self.superclasses.insert(class, id);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                self.classes.push(class);
+                for method in methods {
+                    if let Stmt::Function(method_name, params, body) = method {
+                        let detail = format!(
+                            "{}.{}({})",
+                            name.lexeme,
+                            method_name.lexeme,
+                            lexemes(params)
+                        );
+                        let visible = (method_name.span.start, method_name.span.end);
+                        let method = self.add(method_name, SymbolKind::Method, detail, visible);
+                        self.symbols[method].container = Some(class);
+                        self.function(params, body);
+                    }
+                }
+                self.classes.pop();
+            }
+            Stmt::Expression(exp) | Stmt::Print(_, exp) => self.expression(exp),
+            Stmt::Function(name, params, body) => {
+                let detail = format!("fun {}({})", name.lexeme, lexemes(params));
+                self.declare(name, SymbolKind::Function, detail);
+                self.function(params, body);
+            }
+            Stmt::If(_, condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::Return(_, value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Var(name, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.declare(name, SymbolKind::Variable, format!("var {}", name.lexeme));
+            }
+            Stmt::While(_, condition, body) => {
+                self.expression(condition);
+                self.statement(body);
+            }
+        }
+    }
+
+    fn function(&mut self, params: &[Token], body: &[Stmt]) {
+        self.scopes.push(SymbolMap::default());
+        for param in params {
+            // Parameters sit before the body's braces, so they're visible up
+            // to the end of the next block rather than the enclosing one
+            let end = self
+                .braces
+                .iter()
+                .find(|(open, _)| *open > param.span.start)
+                .map_or(self.source_len, |(_, close)| *close);
+            let detail = format!("parameter {}", param.lexeme);
+            let id = self.add(param, SymbolKind::Parameter, detail, (param.span.start, end));
+            self.bind(param, id);
+        }
+        self.statements(body);
+        self.scopes.pop();
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign(name, value, _) => {
+                self.expression(value);
+                self.reference(name);
+            }
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Call(callee, _, arguments) => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            Expr::Get(object, name) => {
+                let class = match object.as_ref() {
+                    Expr::This(..) => self.classes.last().copied(),
+                    _ => None,
+                };
+                self.properties.push((name.clone(), class));
+                self.expression(object);
+            }
+            Expr::Grouping(exp) | Expr::Unary(_, exp) | Expr::Postfix(exp, _) => {
+                self.expression(exp)
+            }
+            Expr::Set(object, _, value) | Expr::CompoundSet(object, _, _, value) => {
+                self.expression(object);
+                self.expression(value);
+            }
+            Expr::Variable(name, _) => self.reference(name),
+            Expr::Super(_, method, _) => {
+                let superclass = self.classes.last().and_then(|class| self.superclasses.get(class));
+                if let Some(&superclass) = superclass {
+                    self.properties.push((method.clone(), Some(superclass)));
+                }
+            }
+            Expr::Literal(_) | Expr::This(..) => (),
+        }
+    }
+
+    // Declares a name in the innermost scope; it can be used from here to
+    // the end of the enclosing block, or anywhere when it's global
+    fn declare(&mut self, name: &Token, kind: SymbolKind, detail: String) -> usize {
+        let visible = if self.scopes.is_empty() {
+            (0, self.source_len)
+        } else {
+            let end = self
+                .braces
+                .iter()
+                .filter(|(open, close)| *open < name.span.start && name.span.start < *close)
+                .max_by_key(|(open, _)| *open)
+                .map_or(self.source_len, |(_, close)| *close);
+            (name.span.start, end)
+        };
+        let id = self.add(name, kind, detail, visible);
+        self.bind(name, id);
+        id
+    }
+
+    fn add(&mut self, name: &Token, kind: SymbolKind, detail: String, visible: (usize, usize)) -> usize {
+        self.symbols.push(Symbol {
+            name: name.lexeme.to_string(),
+            kind,
+            detail,
+            declaration: name.span,
+            references: Vec::new(),
+            global: self.scopes.is_empty() && kind != SymbolKind::Method,
+            container: None,
+            visible,
+        });
+        self.symbols.len() - 1
+    }
+
+    fn bind(&mut self, name: &Token, id: usize) {
+        match self.scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(name.lexeme.symbol(), id);
+            }
+            // A redeclared global is still the same variable
+            None => {
+                self.globals.entry(name.lexeme.symbol()).or_insert(id);
+            }
+        }
+    }
+
+    fn reference(&mut self, name: &Token) {
+        match self.local(name) {
+            Some(id) => self.symbols[id].references.push(name.span),
+            None => self.unresolved.push(name.clone()),
+        }
+    }
+
+    fn local(&self, name: &Token) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.lexeme.symbol()).copied())
+    }
+
+    // The symbol a name refers to so far
+    fn lookup(&self, name: &Token) -> Option<usize> {
+        self.local(name)
+            .or_else(|| self.globals.get(&name.lexeme.symbol()).copied())
+    }
+
+    // The method a property name refers to: found in the class or the
+    // classes it inherits from when that's known, otherwise the only method
+    // with that name
+    fn method(&self, name: &str, class: Option<usize>) -> Option<usize> {
+        let named = |id: &usize| {
+            let symbol = &self.symbols[*id];
+            symbol.kind == SymbolKind::Method && symbol.name == name
+        };
+        let Some(mut class) = class else {
+            let mut methods = (0..self.symbols.len()).filter(named);
+            return match (methods.next(), methods.next()) {
+                (Some(id), None) => Some(id),
+                _ => None,
+            };
+        };
+        // Bounded in case broken code makes the superclasses a cycle
+        for _ in 0..self.symbols.len() {
+            let found = (0..self.symbols.len())
+                .filter(named)
+                .find(|id| self.symbols[*id].container == Some(class));
+            if found.is_some() {
+                return found;
+            }
+            class = *self.superclasses.get(&class)?;
+        }
+        None
+    }
+}
+
+fn brace_pairs(tokens: &[Token]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let mut open = Vec::new();
+    for token in tokens {
+        match token.token_type {
+            TokenType::LeftBrace => open.push(token.span.start),
+            TokenType::RightBrace => {
+                if let Some(start) = open.pop() {
+                    pairs.push((start, token.span.start));
+                }
+            }
+            _ => (),
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+fn lexemes(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| token.lexeme.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
@@ -0,0 +1,496 @@
+use std::rc::Rc;
+
+use crate::json::Json;
+use crate::parser::{Expr, Stmt};
+use crate::rox_type::RoxType;
+use crate::token::{Span, Token};
+use crate::token_type::TokenType;
+
+// Bumped whenever the shape of the encoding changes. Version 2 added the
+// keyword to If, Print and While, version 3 CompoundSet, version 4 Postfix.
+pub const AST_JSON_VERSION: u32 = 4;
+const FORMAT: &str = "rox-ast";
+
+// Encodes a program as
+// {"format": "rox-ast", "version": 4, "statements": [...]}
+// where every node is an object tagged by "kind" and every token keeps its
+// type, lexeme and span
+pub fn to_json(statements: &[Stmt]) -> Json {
+    Json::object(vec![
+        ("format", Json::string(FORMAT)),
+        ("version", Json::Number(AST_JSON_VERSION as f64)),
+        ("statements", stmts_to_json(statements)),
+    ])
+}
+
+// Decodes a program produced by to_json
+pub fn from_json(json: &Json) -> Result<Vec<Stmt>, String> {
+    if json.get("format").and_then(Json::as_str) != Some(FORMAT) {
+        return Err(String::from("not a rox syntax tree"));
+    }
+    match json.get("version").and_then(Json::as_f64) {
+        Some(version) if version == AST_JSON_VERSION as f64 => (),
+        Some(version) => {
+            return Err(format!(
+                "unsupported syntax tree version {} (expected {})",
+                version, AST_JSON_VERSION
+            ))
+        }
+        None => return Err(String::from("missing syntax tree version")),
+    }
+    stmts_from_json(field(json, "statements")?)
+}
+
+fn stmts_to_json(statements: &[Stmt]) -> Json {
+    Json::Array(statements.iter().map(stmt_to_json).collect())
+}
+
+fn stmt_to_json(stmt: &Stmt) -> Json {
+    match stmt {
+        Stmt::Block(statements) => Json::object(vec![
+            ("kind", Json::string("Block")),
+            ("statements", stmts_to_json(statements)),
+        ]),
+        Stmt::Class(name, superclass, methods) => Json::object(vec![
+            ("kind", Json::string("Class")),
+            ("name", token_to_json(name)),
+            ("superclass", optional_expr_to_json(superclass)),
+            ("methods", stmts_to_json(methods)),
+        ]),
+        Stmt::Expression(exp) => Json::object(vec![
+            ("kind", Json::string("Expression")),
+            ("expression", expr_to_json(exp)),
+        ]),
+        Stmt::Function(name, params, body) => Json::object(vec![
+            ("kind", Json::string("Function")),
+            ("name", token_to_json(name)),
+            ("params", Json::Array(params.iter().map(token_to_json).collect())),
+            ("body", stmts_to_json(body)),
+        ]),
+        Stmt::If(keyword, condition, then_branch, else_branch) => Json::object(vec![
+            ("kind", Json::string("If")),
+            ("keyword", token_to_json(keyword)),
+            ("condition", expr_to_json(condition)),
+            ("then", stmt_to_json(then_branch)),
+            (
+                "else",
+                else_branch.as_ref().map_or(Json::Null, |stmt| stmt_to_json(stmt)),
+            ),
+        ]),
+        Stmt::Print(keyword, exp) => Json::object(vec![
+            ("kind", Json::string("Print")),
+            ("keyword", token_to_json(keyword)),
+            ("expression", expr_to_json(exp)),
+        ]),
+        Stmt::Return(keyword, value) => Json::object(vec![
+            ("kind", Json::string("Return")),
+            ("keyword", token_to_json(keyword)),
+            ("value", optional_expr_to_json(value)),
+        ]),
+        Stmt::Var(name, initializer) => Json::object(vec![
+            ("kind", Json::string("Var")),
+            ("name", token_to_json(name)),
+            ("initializer", optional_expr_to_json(initializer)),
+        ]),
+        Stmt::While(keyword, condition, body) => Json::object(vec![
+            ("kind", Json::string("While")),
+            ("keyword", token_to_json(keyword)),
+            ("condition", expr_to_json(condition)),
+            ("body", stmt_to_json(body)),
+        ]),
+    }
+}
+
+fn expr_to_json(expr: &Expr) -> Json {
+    match expr {
+        Expr::Assign(name, value, depth) => Json::object(vec![
+            ("kind", Json::string("Assign")),
+            ("name", token_to_json(name)),
+            ("value", expr_to_json(value)),
+            ("depth", depth_to_json(depth)),
+        ]),
+        Expr::Binary(left, operator, right) => Json::object(vec![
+            ("kind", Json::string("Binary")),
+            ("left", expr_to_json(left)),
+            ("operator", token_to_json(operator)),
+            ("right", expr_to_json(right)),
+        ]),
+        Expr::Call(callee, paren, arguments) => Json::object(vec![
+            ("kind", Json::string("Call")),
+            ("callee", expr_to_json(callee)),
+            ("paren", token_to_json(paren)),
+            ("arguments", Json::Array(arguments.iter().map(expr_to_json).collect())),
+        ]),
+        Expr::Get(object, name) => Json::object(vec![
+            ("kind", Json::string("Get")),
+            ("object", expr_to_json(object)),
+            ("name", token_to_json(name)),
+        ]),
+        Expr::Grouping(exp) => Json::object(vec![
+            ("kind", Json::string("Grouping")),
+            ("expression", expr_to_json(exp)),
+        ]),
+        Expr::Literal(value) => Json::object(vec![
+            ("kind", Json::string("Literal")),
+            ("value", value_to_json(value)),
+        ]),
+        Expr::Logical(left, operator, right) => Json::object(vec![
+            ("kind", Json::string("Logical")),
+            ("left", expr_to_json(left)),
+            ("operator", token_to_json(operator)),
+            ("right", expr_to_json(right)),
+        ]),
+        Expr::Set(object, name, value) => Json::object(vec![
+            ("kind", Json::string("Set")),
+            ("object", expr_to_json(object)),
+            ("name", token_to_json(name)),
+            ("value", expr_to_json(value)),
+        ]),
+        Expr::CompoundSet(object, name, operator, value) => Json::object(vec![
+            ("kind", Json::string("CompoundSet")),
+            ("object", expr_to_json(object)),
+            ("name", token_to_json(name)),
+            ("operator", token_to_json(operator)),
+            ("value", expr_to_json(value)),
+        ]),
+        Expr::Postfix(target, operator) => Json::object(vec![
+            ("kind", Json::string("Postfix")),
+            ("target", expr_to_json(target)),
+            ("operator", token_to_json(operator)),
+        ]),
+        Expr::Super(keyword, method, depth) => Json::object(vec![
+            ("kind", Json::string("Super")),
+            ("keyword", token_to_json(keyword)),
+            ("method", token_to_json(method)),
+            ("depth", depth_to_json(depth)),
+        ]),
+        Expr::This(keyword, depth) => Json::object(vec![
+            ("kind", Json::string("This")),
+            ("keyword", token_to_json(keyword)),
+            ("depth", depth_to_json(depth)),
+        ]),
+        Expr::Unary(operator, right) => Json::object(vec![
+            ("kind", Json::string("Unary")),
+            ("operator", token_to_json(operator)),
+            ("right", expr_to_json(right)),
+        ]),
+        Expr::Variable(name, depth) => Json::object(vec![
+            ("kind", Json::string("Variable")),
+            ("name", token_to_json(name)),
+            ("depth", depth_to_json(depth)),
+        ]),
+    }
+}
+
+fn optional_expr_to_json(expr: &Option<Box<Expr>>) -> Json {
+    expr.as_ref().map_or(Json::Null, |expr| expr_to_json(expr))
+}
+
+fn depth_to_json(depth: &Option<usize>) -> Json {
+    depth.map_or(Json::Null, |depth| Json::Number(depth as f64))
+}
+
+fn token_to_json(token: &Token) -> Json {
+    let mut fields = vec![
+        ("type", Json::string(&format!("{:?}", token.token_type))),
+        ("lexeme", Json::string(&token.lexeme)),
+        (
+            "span",
+            Json::object(vec![
+                ("start", Json::Number(token.span.start as f64)),
+                ("end", Json::Number(token.span.end as f64)),
+                ("line", Json::Number(token.span.line as f64)),
+                ("column", Json::Number(token.span.column as f64)),
+            ]),
+        ),
+    ];
+    if !matches!(token.literal, RoxType::Nil) {
+        fields.push(("literal", value_to_json(&token.literal)));
+    }
+    Json::object(fields)
+}
+
+fn value_to_json(value: &RoxType) -> Json {
+    match value {
+        RoxType::String(s) => Json::string(s),
+        RoxType::Number(n) => Json::Number(*n),
+        RoxType::Boolean(b) => Json::Bool(*b),
+        // Only primitive values appear as literals in a syntax tree
+        _ => Json::Null,
+    }
+}
+
+fn stmts_from_json(json: &Json) -> Result<Vec<Stmt>, String> {
+    array(json)?.iter().map(stmt_from_json).collect()
+}
+
+fn stmt_from_json(json: &Json) -> Result<Stmt, String> {
+    let stmt = match kind(json)? {
+        "Block" => Stmt::Block(stmts_from_json(field(json, "statements")?)?),
+        "Class" => Stmt::Class(
+            token_from_json(field(json, "name")?)?,
+            optional_expr_from_json(field(json, "superclass")?)?,
+            stmts_from_json(field(json, "methods")?)?,
+        ),
+        "Expression" => Stmt::Expression(boxed_expr(json, "expression")?),
+        "Function" => Stmt::Function(
+            token_from_json(field(json, "name")?)?,
+            array(field(json, "params")?)?
+                .iter()
+                .map(token_from_json)
+                .collect::<Result<Rc<[Token]>, String>>()?,
+            stmts_from_json(field(json, "body")?)?.into(),
+        ),
+        "If" => Stmt::If(
+            token_from_json(field(json, "keyword")?)?,
+            boxed_expr(json, "condition")?,
+            Box::new(stmt_from_json(field(json, "then")?)?),
+            match field(json, "else")? {
+                Json::Null => None,
+                stmt => Some(Box::new(stmt_from_json(stmt)?)),
+            },
+        ),
+        "Print" => Stmt::Print(
+            token_from_json(field(json, "keyword")?)?,
+            boxed_expr(json, "expression")?,
+        ),
+        "Return" => Stmt::Return(
+            token_from_json(field(json, "keyword")?)?,
+            optional_expr_from_json(field(json, "value")?)?,
+        ),
+        "Var" => Stmt::Var(
+            token_from_json(field(json, "name")?)?,
+            optional_expr_from_json(field(json, "initializer")?)?,
+        ),
+        "While" => Stmt::While(
+            token_from_json(field(json, "keyword")?)?,
+            boxed_expr(json, "condition")?,
+            Box::new(stmt_from_json(field(json, "body")?)?),
+        ),
+        other => return Err(format!("unknown statement kind '{}'", other)),
+    };
+    Ok(stmt)
+}
+
+fn expr_from_json(json: &Json) -> Result<Expr, String> {
+    let expr = match kind(json)? {
+        "Assign" => Expr::Assign(
+            token_from_json(field(json, "name")?)?,
+            boxed_expr(json, "value")?,
+            depth_from_json(json)?,
+        ),
+        "Binary" => Expr::Binary(
+            boxed_expr(json, "left")?,
+            token_from_json(field(json, "operator")?)?,
+            boxed_expr(json, "right")?,
+        ),
+        "Call" => Expr::Call(
+            boxed_expr(json, "callee")?,
+            token_from_json(field(json, "paren")?)?,
+            array(field(json, "arguments")?)?
+                .iter()
+                .map(expr_from_json)
+                .collect::<Result<Vec<Expr>, String>>()?,
+        ),
+        "Get" => Expr::Get(
+            boxed_expr(json, "object")?,
+            token_from_json(field(json, "name")?)?,
+        ),
+        "Grouping" => Expr::Grouping(boxed_expr(json, "expression")?),
+        "Literal" => Expr::Literal(value_from_json(field(json, "value")?)?),
+        "Logical" => Expr::Logical(
+            boxed_expr(json, "left")?,
+            token_from_json(field(json, "operator")?)?,
+            boxed_expr(json, "right")?,
+        ),
+        "Set" => Expr::Set(
+            boxed_expr(json, "object")?,
+            token_from_json(field(json, "name")?)?,
+            boxed_expr(json, "value")?,
+        ),
+        "CompoundSet" => Expr::CompoundSet(
+            boxed_expr(json, "object")?,
+            token_from_json(field(json, "name")?)?,
+            token_from_json(field(json, "operator")?)?,
+            boxed_expr(json, "value")?,
+        ),
+        "Postfix" => Expr::Postfix(
+            boxed_expr(json, "target")?,
+            token_from_json(field(json, "operator")?)?,
+        ),
+        "Super" => Expr::Super(
+            token_from_json(field(json, "keyword")?)?,
+            token_from_json(field(json, "method")?)?,
+            depth_from_json(json)?,
+        ),
+        "This" => Expr::This(
+            token_from_json(field(json, "keyword")?)?,
+            depth_from_json(json)?,
+        ),
+        "Unary" => Expr::Unary(
+            token_from_json(field(json, "operator")?)?,
+            boxed_expr(json, "right")?,
+        ),
+        "Variable" => Expr::Variable(
+            token_from_json(field(json, "name")?)?,
+            depth_from_json(json)?,
+        ),
+        other => return Err(format!("unknown expression kind '{}'", other)),
+    };
+    Ok(expr)
+}
+
+fn boxed_expr(json: &Json, key: &str) -> Result<Box<Expr>, String> {
+    Ok(Box::new(expr_from_json(field(json, key)?)?))
+}
+
+fn optional_expr_from_json(json: &Json) -> Result<Option<Box<Expr>>, String> {
+    match json {
+        Json::Null => Ok(None),
+        expr => Ok(Some(Box::new(expr_from_json(expr)?))),
+    }
+}
+
+fn depth_from_json(json: &Json) -> Result<Option<usize>, String> {
+    match field(json, "depth")? {
+        Json::Null => Ok(None),
+        depth => Ok(Some(number(depth)? as usize)),
+    }
+}
+
+fn token_from_json(json: &Json) -> Result<Token, String> {
+    let type_name = string(field(json, "type")?)?;
+    let token_type = TokenType::from_name(type_name)
+        .ok_or_else(|| format!("unknown token type '{}'", type_name))?;
+    let span = field(json, "span")?;
+    let span = Span {
+        start: number(field(span, "start")?)? as usize,
+        end: number(field(span, "end")?)? as usize,
+        line: number(field(span, "line")?)? as u32,
+        column: number(field(span, "column")?)? as u32,
+    };
+    let literal = match json.get("literal") {
+        Some(literal) => value_from_json(literal)?,
+        None => RoxType::Nil,
+    };
+    Ok(Token::new(
+        token_type,
+        string(field(json, "lexeme")?)?,
+        literal,
+        span,
+    ))
+}
+
+fn value_from_json(json: &Json) -> Result<RoxType, String> {
+    match json {
+        Json::Null => Ok(RoxType::Nil),
+        Json::Bool(b) => Ok(RoxType::Boolean(*b)),
+        Json::Number(n) => Ok(RoxType::Number(*n)),
+        Json::String(s) => Ok(RoxType::String(s.clone())),
+        _ => Err(String::from("literal values must be null, booleans, numbers or strings")),
+    }
+}
+
+fn kind(json: &Json) -> Result<&str, String> {
+    string(field(json, "kind")?)
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("missing field '{}'", key))
+}
+
+fn array(json: &Json) -> Result<&[Json], String> {
+    json.as_array().ok_or_else(|| String::from("expected an array"))
+}
+
+fn string(json: &Json) -> Result<&str, String> {
+    json.as_str().ok_or_else(|| String::from("expected a string"))
+}
+
+fn number(json: &Json) -> Result<f64, String> {
+    json.as_f64().ok_or_else(|| String::from("expected a number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_printer::print_program;
+    use crate::Rox;
+
+    // Touches every kind of statement and expression
+    const PROGRAM: &str = r#"
+class Base {
+    init(name) { this.name = name; }
+    greet() { return "hi " + this.name; }
+}
+class Derived < Base {
+    greet() { return super.greet() + "!"; }
+}
+fun count(n) {
+    var total = 0;
+    while (total < n) total = total + 1;
+    if (total == n and !false) print total; else print nil;
+    {
+        var d = Derived("x");
+        d.name += "y";
+        d.extra = -1.5;
+        print d.extra++ + total--;
+        print d.greet() or (n >= 2);
+    }
+    return total;
+}
+print count(3);
+"#;
+
+    fn with_fields(json: &Json, replaced: &[(&str, Json)]) -> Json {
+        let Json::Object(fields) = json else {
+            panic!("the tree is an object");
+        };
+        let mut fields = fields.clone();
+        for (key, value) in replaced {
+            for field in fields.iter_mut().filter(|field| field.0 == *key) {
+                field.1 = value.clone();
+            }
+        }
+        Json::Object(fields)
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let statements = Rox::compile(PROGRAM).unwrap();
+        let text = to_json(&statements).to_string();
+        let decoded = from_json(&Json::parse(&text).unwrap()).unwrap();
+        assert_eq!(to_json(&decoded).to_string(), text);
+        assert_eq!(print_program(&decoded), print_program(&statements));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let json = to_json(&Rox::compile(PROGRAM).unwrap());
+        let other = with_fields(&json, &[("format", Json::string("something-else"))]);
+        assert_eq!(from_json(&other).unwrap_err(), "not a rox syntax tree");
+        assert!(from_json(&Json::parse("[]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let json = to_json(&Rox::compile(PROGRAM).unwrap());
+        let older = with_fields(&json, &[("version", Json::Number(2.0))]);
+        assert_eq!(
+            from_json(&older).unwrap_err(),
+            format!("unsupported syntax tree version 2 (expected {})", AST_JSON_VERSION)
+        );
+        let Json::Object(fields) = &json else {
+            panic!("the tree is an object");
+        };
+        let unversioned = fields
+            .iter()
+            .filter(|field| field.0 != "version")
+            .cloned()
+            .collect();
+        assert_eq!(
+            from_json(&Json::Object(unversioned)).unwrap_err(),
+            "missing syntax tree version"
+        );
+    }
+}
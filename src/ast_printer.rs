@@ -0,0 +1,119 @@
+use crate::parser::{Expr, Stmt};
+use crate::rox_type::RoxType;
+use crate::token::Token;
+
+// Renders syntax trees as parenthesized prefix expressions, one line per
+// top-level statement, e.g. `(print (+ 1 (group (* 2 3))))`
+pub fn print_program(statements: &[Stmt]) -> String {
+    statements
+        .iter()
+        .map(print_stmt)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn print_stmt(stmt: &Stmt) -> String {
+    match stmt {
+        Stmt::Block(statements) => parenthesize_stmts("block", statements),
+        Stmt::Class(name, superclass, methods) => {
+            let mut header = format!("class {}", name.lexeme);
+            if let Some(superclass) = superclass {
+                header.push_str(&format!(" < {}", print_expr(superclass)));
+            }
+            parenthesize_stmts(&header, methods)
+        }
+        Stmt::Expression(exp) => parenthesize(";", &[exp]),
+        Stmt::Function(name, params, body) => {
+            let header = format!("fun {} ({})", name.lexeme, lexemes(params));
+            parenthesize_stmts(&header, body)
+        }
+        Stmt::If(_, condition, then_branch, else_branch) => {
+            let mut s = format!("(if {} {}", print_expr(condition), print_stmt(then_branch));
+            if let Some(else_branch) = else_branch {
+                s.push_str(&format!(" {}", print_stmt(else_branch)));
+            }
+            s.push(')');
+            s
+        }
+        Stmt::Print(_, exp) => parenthesize("print", &[exp]),
+        Stmt::Return(_, exp) => match exp {
+            Some(exp) => parenthesize("return", &[exp]),
+            None => String::from("(return)"),
+        },
+        Stmt::Var(name, initializer) => match initializer {
+            Some(initializer) => parenthesize(&format!("var {}", name.lexeme), &[initializer]),
+            None => format!("(var {})", name.lexeme),
+        },
+        Stmt::While(_, condition, body) => {
+            format!("(while {} {})", print_expr(condition), print_stmt(body))
+        }
+    }
+}
+
+pub fn print_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Assign(name, value, _) => parenthesize(&format!("= {}", name.lexeme), &[value]),
+        Expr::Binary(left, operator, right) | Expr::Logical(left, operator, right) => {
+            parenthesize(&operator.lexeme, &[left, right])
+        }
+        Expr::Call(callee, _, arguments) => {
+            let mut parts = vec![print_expr(callee)];
+            parts.extend(arguments.iter().map(print_expr));
+            format!("(call {})", parts.join(" "))
+        }
+        Expr::Get(object, name) => format!("(. {} {})", print_expr(object), name.lexeme),
+        Expr::Grouping(exp) => parenthesize("group", &[exp]),
+        Expr::Literal(value) => match value {
+            RoxType::String(s) => format!("{:?}", s),
+            other => other.to_string(),
+        },
+        Expr::Set(object, name, value) => format!(
+            "(.= {} {} {})",
+            print_expr(object),
+            name.lexeme,
+            print_expr(value)
+        ),
+        Expr::CompoundSet(object, name, operator, value) => format!(
+            "(.{}= {} {} {})",
+            operator.lexeme,
+            print_expr(object),
+            name.lexeme,
+            print_expr(value)
+        ),
+        Expr::Postfix(target, operator) => {
+            format!("(post{0}{0} {1})", operator.lexeme, print_expr(target))
+        }
+        Expr::Super(_, method, _) => format!("(super {})", method.lexeme),
+        Expr::This(_, _) => String::from("this"),
+        Expr::Unary(operator, right) => parenthesize(&operator.lexeme, &[right]),
+        Expr::Variable(name, _) => name.lexeme.to_string(),
+    }
+}
+
+fn parenthesize<E: AsRef<Expr>>(name: &str, exprs: &[E]) -> String {
+    let mut s = format!("({}", name);
+    for expr in exprs {
+        s.push(' ');
+        s.push_str(&print_expr(expr.as_ref()));
+    }
+    s.push(')');
+    s
+}
+
+fn parenthesize_stmts(name: &str, statements: &[Stmt]) -> String {
+    let mut s = format!("({}", name);
+    for stmt in statements {
+        s.push(' ');
+        s.push_str(&print_stmt(stmt));
+    }
+    s.push(')');
+    s
+}
+
+fn lexemes(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| token.lexeme.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::class::RoxInstance;
+use crate::gc::Tracer;
+use crate::interpreter::{Environment, Interpreter, InterpreterResult, Unwind};
+use crate::parser::Stmt;
+use crate::rox_type::RoxType;
+use crate::symbol::Symbol;
+use crate::token::Token;
+
+// Anything that can be invoked with a call expression
+pub trait Callable: fmt::Debug + fmt::Display {
+    fn arity(&self) -> usize;
+    // Used to label this callable's frame in runtime error tracebacks
+    fn name(&self) -> String;
+    fn call(self: Rc<Self>, interpreter: &mut Interpreter, arguments: Vec<RoxType>)
+        -> InterpreterResult;
+    // Reports the values this callable keeps alive to the cycle collector.
+    // Anything left out is kept alive for as long as the callable is.
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+// A function declared in a Lox script
+pub struct RoxFunction {
+    name: Token,
+    params: Rc<[Token]>,
+    body: Rc<[Stmt]>,
+    closure: Rc<RefCell<Environment>>,
+    is_initializer: bool,
+}
+
+impl RoxFunction {
+    pub(crate) fn new(
+        name: Token,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
+        closure: Rc<RefCell<Environment>>,
+        is_initializer: bool,
+    ) -> RoxFunction {
+        RoxFunction {
+            name,
+            params,
+            body,
+            closure,
+            is_initializer,
+        }
+    }
+
+    // Produces a method whose closure has 'this' bound to the instance
+    pub fn bind(&self, instance: Rc<RefCell<RoxInstance>>) -> RoxFunction {
+        let mut environment = Environment::with_enclosing(self.closure.clone());
+        environment.define(Symbol::THIS, RoxType::Instance(instance));
+        RoxFunction::new(
+            self.name.clone(),
+            self.params.clone(),
+            self.body.clone(),
+            Rc::new(RefCell::new(environment)),
+            self.is_initializer,
+        )
+    }
+
+    // Initializers always evaluate to the instance they were bound to
+    fn this(&self) -> RoxType {
+        self.closure
+            .borrow()
+            .get_local(Symbol::THIS)
+            .expect("initializer is bound to an instance")
+    }
+}
+
+impl Callable for RoxFunction {
+    fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    fn name(&self) -> String {
+        self.name.lexeme.to_string()
+    }
+
+    fn call(
+        self: Rc<Self>,
+        interpreter: &mut Interpreter,
+        arguments: Vec<RoxType>,
+    ) -> InterpreterResult {
+        let mut environment = Environment::with_enclosing(self.closure.clone());
+        for (param, argument) in self.params.iter().zip(arguments) {
+            environment.define(param.lexeme.symbol(), argument);
+        }
+
+        match interpreter.execute_block(&self.body, Rc::new(RefCell::new(environment))) {
+            Ok(()) | Err(Unwind::Return(_)) if self.is_initializer => Ok(self.this()),
+            Ok(()) => Ok(RoxType::Nil),
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Error(e)) => Err(e),
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.environment(&self.closure);
+    }
+}
+
+// The closure can refer back to this function, so it is left out
+impl fmt::Debug for RoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RoxFunction")
+            .field("name", &self.name.lexeme)
+            .field("params", &self.params.len())
+            .finish()
+    }
+}
+
+impl fmt::Display for RoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<fn {}>", self.name.lexeme)
+    }
+}
+
+pub type NativeFn = dyn Fn(&[RoxType]) -> InterpreterResult;
+
+// A function implemented in Rust and exposed to scripts as a global
+pub struct NativeFunction {
+    name: String,
+    arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: usize, function: Box<NativeFn>) -> NativeFunction {
+        NativeFunction {
+            name: String::from(name),
+            arity,
+            function,
+        }
+    }
+
+    // Natives don't need the interpreter, so the VM calls them this way too
+    pub(crate) fn call_native(&self, arguments: &[RoxType]) -> InterpreterResult {
+        (self.function)(arguments)
+    }
+}
+
+impl Callable for NativeFunction {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn call(
+        self: Rc<Self>,
+        _interpreter: &mut Interpreter,
+        arguments: Vec<RoxType>,
+    ) -> InterpreterResult {
+        self.call_native(&arguments)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl fmt::Display for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
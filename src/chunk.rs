@@ -0,0 +1,310 @@
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::token::Token;
+
+// Instructions for the bytecode VM. Operands follow the opcode byte: u16
+// for constants, globals and jumps, u8 for local and upvalue slots and
+// argument counts.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    Dup,
+    // Swaps the top two values
+    Swap,
+    // Pushes a copy of the value under the top
+    Over,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    // Followed by a function constant, then an (is_local, index) byte pair
+    // for each of its upvalues
+    Closure,
+    CloseUpvalue,
+    Return,
+    Class,
+    Inherit,
+    Method,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 41] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::Dup,
+        OpCode::Swap,
+        OpCode::Over,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::NotEqual,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+
+    fn mnemonic(&self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::Dup => "OP_DUP",
+            OpCode::Swap => "OP_SWAP",
+            OpCode::Over => "OP_OVER",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Class => "OP_CLASS",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::Method => "OP_METHOD",
+        }
+    }
+}
+
+// Values known when compiling
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    // Offset where each run of code compiled for one token starts, and the
+    // token, which runtime errors point at
+    pub tokens: Vec<(usize, Token)>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, token: Option<&Token>) {
+        if let Some(token) = token {
+            let same = self.tokens.last().is_some_and(|(_, last)| {
+                last.span == token.span && last.lexeme == token.lexeme
+            });
+            if !same {
+                self.tokens.push((self.code.len(), token.clone()));
+            }
+        }
+        self.code.push(byte);
+    }
+
+    pub fn token_at(&self, offset: usize) -> Option<&Token> {
+        let run = self.tokens.partition_point(|(start, _)| *start <= offset);
+        run.checked_sub(1).map(|run| &self.tokens[run].1)
+    }
+
+    pub fn line_at(&self, offset: usize) -> u32 {
+        self.token_at(offset).map_or(0, |token| token.span.line)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    // Empty for the top-level script
+    pub name: Rc<str>,
+    pub arity: u8,
+    pub upvalue_count: u8,
+    pub chunk: Chunk,
+}
+
+// A compiled script. Globals are numbered at compile time; the names are
+// kept for error messages and to find natives.
+#[derive(Debug)]
+pub struct Program {
+    pub script: Rc<Function>,
+    pub globals: Vec<Rc<str>>,
+}
+
+// Lists every function's instructions, clox style
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    disassemble_function(program, &program.script, &mut out);
+    out
+}
+
+fn disassemble_function(program: &Program, function: &Function, out: &mut String) {
+    let name = if function.name.is_empty() {
+        "<script>"
+    } else {
+        &function.name
+    };
+    let _ = writeln!(out, "== {} ==", name);
+    let chunk = &function.chunk;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(program, chunk, offset, out);
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            out.push('\n');
+            disassemble_function(program, function, out);
+        }
+    }
+}
+
+// Returns the offset of the next instruction
+fn disassemble_instruction(program: &Program, chunk: &Chunk, offset: usize, out: &mut String) -> usize {
+    let line = chunk.line_at(offset);
+    if offset > 0 && chunk.line_at(offset - 1) == line {
+        let _ = write!(out, "{:04}    | ", offset);
+    } else {
+        let _ = write!(out, "{:04} {:4} ", offset, line);
+    }
+    let Some(op) = OpCode::from_byte(chunk.code[offset]) else {
+        let _ = writeln!(out, "Unknown opcode {}", chunk.code[offset]);
+        return offset + 1;
+    };
+    let name = op.mnemonic();
+    match op {
+        OpCode::Constant | OpCode::GetProperty | OpCode::SetProperty | OpCode::GetSuper
+        | OpCode::Class | OpCode::Method => {
+            let index = chunk.read_u16(offset + 1);
+            let constant = match &chunk.constants[index as usize] {
+                Constant::Number(n) => n.to_string(),
+                Constant::String(s) => s.to_string(),
+                Constant::Function(f) => format!("<fn {}>", f.name),
+            };
+            let _ = writeln!(out, "{:<16} {:4} '{}'", name, index, constant);
+            offset + 3
+        }
+        OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal => {
+            let index = chunk.read_u16(offset + 1);
+            let global = program.globals.get(index as usize).map_or("?", |name| name);
+            let _ = writeln!(out, "{:<16} {:4} '{}'", name, index, global);
+            offset + 3
+        }
+        OpCode::GetLocal | OpCode::SetLocal | OpCode::GetUpvalue | OpCode::SetUpvalue
+        | OpCode::Call => {
+            let _ = writeln!(out, "{:<16} {:4}", name, chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse => {
+            let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(out, "{:<16} {:4} -> {}", name, offset, target);
+            offset + 3
+        }
+        OpCode::Loop => {
+            let target = (offset + 3).saturating_sub(chunk.read_u16(offset + 1) as usize);
+            let _ = writeln!(out, "{:<16} {:4} -> {}", name, offset, target);
+            offset + 3
+        }
+        OpCode::Closure => {
+            let index = chunk.read_u16(offset + 1);
+            let Constant::Function(function) = &chunk.constants[index as usize] else {
+                let _ = writeln!(out, "{:<16} {:4} (not a function)", name, index);
+                return offset + 3;
+            };
+            let _ = writeln!(out, "{:<16} {:4} <fn {}>", name, index, function.name);
+            let mut offset = offset + 3;
+            for _ in 0..function.upvalue_count {
+                let kind = if chunk.code[offset] == 1 { "local" } else { "upvalue" };
+                let _ = writeln!(out, "{:04}    |                     {} {}", offset, kind, chunk.code[offset + 1]);
+                offset += 2;
+            }
+            offset
+        }
+        _ => {
+            let _ = writeln!(out, "{}", name);
+            offset + 1
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::callable::{Callable, RoxFunction};
+use crate::gc::Tracer;
+use crate::interpreter::{Interpreter, InterpreterError, InterpreterResult};
+use crate::rox_type::RoxType;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::token::Token;
+
+#[derive(Debug)]
+pub struct RoxClass {
+    pub name: String,
+    superclass: Option<Rc<RoxClass>>,
+    methods: SymbolMap<Rc<RoxFunction>>,
+}
+
+impl RoxClass {
+    pub fn new(
+        name: String,
+        superclass: Option<Rc<RoxClass>>,
+        methods: SymbolMap<Rc<RoxFunction>>,
+    ) -> RoxClass {
+        RoxClass {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    // Searches this class first, then up the superclass chain
+    pub fn find_method(&self, name: Symbol) -> Option<Rc<RoxFunction>> {
+        if let Some(method) = self.methods.get(&name) {
+            return Some(method.clone());
+        }
+        self.superclass
+            .as_ref()
+            .and_then(|superclass| superclass.find_method(name))
+    }
+}
+
+// Calling a class constructs an instance and runs its initializer, if any
+impl Callable for RoxClass {
+    fn arity(&self) -> usize {
+        match self.find_method(Symbol::INIT) {
+            Some(initializer) => initializer.arity(),
+            None => 0,
+        }
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn call(
+        self: Rc<Self>,
+        interpreter: &mut Interpreter,
+        arguments: Vec<RoxType>,
+    ) -> InterpreterResult {
+        let initializer = self.find_method(Symbol::INIT);
+        let instance = Rc::new(RefCell::new(RoxInstance::new(self)));
+        interpreter.heap.track_instance(&instance);
+        if let Some(initializer) = initializer {
+            Rc::new(initializer.bind(instance.clone())).call(interpreter, arguments)?;
+        }
+        Ok(RoxType::Instance(instance))
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(superclass) = &self.superclass {
+            tracer.class(superclass);
+        }
+        for method in self.methods.values() {
+            tracer.function(method);
+        }
+    }
+}
+
+impl fmt::Display for RoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub struct RoxInstance {
+    class: Rc<RoxClass>,
+    fields: SymbolMap<RoxType>,
+}
+
+impl RoxInstance {
+    fn new(class: Rc<RoxClass>) -> RoxInstance {
+        RoxInstance {
+            class,
+            fields: SymbolMap::default(),
+        }
+    }
+
+    pub fn set(&mut self, name: &Token, value: RoxType) {
+        self.fields.insert(name.lexeme.symbol(), value);
+    }
+
+    // Fields in name order, for debuggers
+    pub fn fields(&self) -> Vec<(String, RoxType)> {
+        let mut fields = self
+            .fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect::<Vec<(String, RoxType)>>();
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        fields
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.class(&self.class);
+        for value in self.fields.values() {
+            tracer.value(value);
+        }
+    }
+
+    // Empties an instance the collector found unreachable
+    pub(crate) fn clear(&mut self) {
+        self.fields.clear();
+    }
+}
+
+// Fields shadow methods; methods come back bound to the instance
+pub fn get_property(instance: &Rc<RefCell<RoxInstance>>, name: &Token) -> InterpreterResult {
+    if let Some(value) = instance.borrow().fields.get(&name.lexeme.symbol()) {
+        return Ok(value.clone());
+    }
+    let method = instance.borrow().class.find_method(name.lexeme.symbol());
+    match method {
+        Some(method) => Ok(RoxType::Callable(Rc::new(method.bind(instance.clone())))),
+        None => Err(InterpreterError::at(name, &format!("Undefined property '{}'.", name.lexeme))),
+    }
+}
+
+// Fields can hold the instance itself, so only their names are shown
+impl fmt::Debug for RoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RoxInstance")
+            .field("class", &self.class.name)
+            .field("fields", &self.fields.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl fmt::Display for RoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
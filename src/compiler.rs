@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Constant, Function, OpCode, Program};
+use crate::parser::{Expr, Stmt};
+use crate::rox_type::RoxType;
+use crate::token::Token;
+use crate::token_type::TokenType;
+
+// Turns a resolved program into bytecode for the VM. The resolver has
+// already rejected invalid programs, so the only errors left are the
+// limits of the instruction format.
+pub fn compile(statements: &[Stmt]) -> Result<Program, Vec<CompileErr>> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionState::new("", FunctionKind::Script)],
+        globals: Vec::new(),
+        global_slots: HashMap::new(),
+        token: None,
+        errors: Vec::new(),
+    };
+    for stmt in statements {
+        compiler.statement(stmt);
+    }
+    compiler.emit_return();
+    let script = compiler.functions.pop().map(|state| state.function);
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(Program {
+        script: Rc::new(script.unwrap_or_default()),
+        globals: compiler.globals,
+    })
+}
+
+#[derive(Debug)]
+pub struct CompileErr {
+    pub err_token: Token,
+    pub err_msg: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    depth: usize,
+    // Closed over by a nested function, so it moves to the heap when it
+    // goes out of scope
+    captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    // A slot of the enclosing function's locals, or one of its upvalues
+    is_local: bool,
+    index: u8,
+}
+
+struct FunctionState<'a> {
+    function: Function,
+    kind: FunctionKind,
+    // Stack slots, with slot 0 holding the callee or 'this'
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Names and string literals already in the constant table
+    strings: HashMap<&'a str, u16>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(name: &str, kind: FunctionKind) -> FunctionState<'a> {
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        FunctionState {
+            function: Function {
+                name: Rc::from(name),
+                ..Function::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: receiver,
+                depth: 0,
+                captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            strings: HashMap::new(),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    // The function being compiled is last, enclosing functions before it
+    functions: Vec<FunctionState<'a>>,
+    globals: Vec<Rc<str>>,
+    global_slots: HashMap<&'a str, u16>,
+    // Latest token seen, which the code emitted next is attributed to, and
+    // where errors are reported
+    token: Option<&'a Token>,
+    errors: Vec<CompileErr>,
+}
+
+impl<'a> Compiler<'a> {
+    fn statement(&mut self, stmt: &'a Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::Class(name, superclass, methods) => self.class(name, superclass.as_deref(), methods),
+            Stmt::Expression(exp) => {
+                self.expression(exp);
+                self.emit(OpCode::Pop);
+            }
+            Stmt::Function(name, params, body) => {
+                self.at(name);
+                // Declared first so the body can call itself
+                let global = self.declare(name);
+                self.function(name, params, body, FunctionKind::Function);
+                self.define(global);
+            }
+            Stmt::If(keyword, condition, then_branch, else_branch) => {
+                self.at(keyword);
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::Print(keyword, exp) => {
+                self.expression(exp);
+                self.at(keyword);
+                self.emit(OpCode::Print);
+            }
+            Stmt::Return(keyword, value) => {
+                self.at(keyword);
+                match value {
+                    Some(value) => {
+                        self.expression(value);
+                        self.emit(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Stmt::Var(name, initializer) => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(OpCode::Nil),
+                }
+                self.at(name);
+                let global = self.declare(name);
+                self.define(global);
+            }
+            Stmt::While(keyword, condition, body) => {
+                self.at(keyword);
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.statement(body);
+                self.at(keyword);
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit(OpCode::Pop);
+            }
+        }
+    }
+
+    fn class(&mut self, name: &'a Token, superclass: Option<&'a Expr>, methods: &'a [Stmt]) {
+        self.at(name);
+        let name_constant = self.string_constant(&name.lexeme);
+        let global = self.declare(name);
+        self.emit_with_u16(OpCode::Class, name_constant);
+        self.define(global);
+
+        // Methods of a subclass capture 'super' from a scope around them
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+            self.begin_scope();
+            self.add_local("super");
+            self.variable(name, false);
+            self.at(name);
+            self.emit(OpCode::Inherit);
+        }
+
+        self.variable(name, false);
+        for method in methods {
+            if let Stmt::Function(method_name, params, body) = method {
+                let kind = if method_name.lexeme == "init" {
+                    FunctionKind::Initializer
+                } else {
+                    FunctionKind::Method
+                };
+                self.function(method_name, params, body, kind);
+                let constant = self.string_constant(&method_name.lexeme);
+                self.emit_with_u16(OpCode::Method, constant);
+            }
+        }
+        self.emit(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    // Compiles a function body and leaves a closure over it on the stack
+    fn function(&mut self, name: &'a Token, params: &'a [Token], body: &'a [Stmt], kind: FunctionKind) {
+        self.functions.push(FunctionState::new(&name.lexeme, kind));
+        self.begin_scope();
+        for param in params {
+            self.at(param);
+            self.add_local(&param.lexeme);
+        }
+        self.current().function.arity = params.len() as u8;
+        for stmt in body {
+            self.statement(stmt);
+        }
+        self.emit_return();
+
+        let Some(state) = self.functions.pop() else {
+            return;
+        };
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len() as u8;
+        self.at(name);
+        let constant = self.make_constant(Constant::Function(Rc::new(function)));
+        self.emit_with_u16(OpCode::Closure, constant);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn expression(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::Assign(name, value, _) => {
+                self.expression(value);
+                self.variable(name, true);
+            }
+            Expr::Binary(left, operator, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.at(operator);
+                self.emit(binary_op(&operator.token_type));
+            }
+            Expr::Call(callee, paren, arguments) => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.at(paren);
+                self.emit(OpCode::Call);
+                self.emit_byte(arguments.len() as u8);
+            }
+            Expr::Get(object, name) => {
+                self.expression(object);
+                self.at(name);
+                let constant = self.string_constant(&name.lexeme);
+                self.emit_with_u16(OpCode::GetProperty, constant);
+            }
+            Expr::Grouping(exp) => self.expression(exp),
+            Expr::Literal(value) => match value {
+                RoxType::Nil => self.emit(OpCode::Nil),
+                RoxType::Boolean(true) => self.emit(OpCode::True),
+                RoxType::Boolean(false) => self.emit(OpCode::False),
+                RoxType::Number(n) => {
+                    let constant = self.make_constant(Constant::Number(*n));
+                    self.emit_with_u16(OpCode::Constant, constant);
+                }
+                RoxType::String(s) => {
+                    let constant = self.string_constant(s);
+                    self.emit_with_u16(OpCode::Constant, constant);
+                }
+                // The parser only makes literals of plain values
+                _ => self.emit(OpCode::Nil),
+            },
+            // Only the operand that decides the result is left on the stack
+            Expr::Logical(left, operator, right) => {
+                self.expression(left);
+                if operator.token_type == TokenType::And {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expr::Set(object, name, value) => {
+                self.expression(object);
+                self.expression(value);
+                self.at(name);
+                let constant = self.string_constant(&name.lexeme);
+                self.emit_with_u16(OpCode::SetProperty, constant);
+            }
+            // The object stays under the old value, ready for the store
+            Expr::CompoundSet(object, name, operator, value) => {
+                self.expression(object);
+                self.emit(OpCode::Dup);
+                self.at(name);
+                let constant = self.string_constant(&name.lexeme);
+                self.emit_with_u16(OpCode::GetProperty, constant);
+                self.expression(value);
+                self.at(operator);
+                self.emit(binary_op(&operator.token_type));
+                self.at(name);
+                self.emit_with_u16(OpCode::SetProperty, constant);
+            }
+            // The old value is kept under the store and left behind
+            Expr::Postfix(target, operator) => match target.as_ref() {
+                Expr::Variable(name, _) => {
+                    self.variable(name, false);
+                    self.emit(OpCode::Dup);
+                    self.step(operator);
+                    self.variable(name, true);
+                    self.emit(OpCode::Pop);
+                }
+                Expr::Get(object, name) => {
+                    self.expression(object);
+                    self.emit(OpCode::Dup);
+                    self.at(name);
+                    let constant = self.string_constant(&name.lexeme);
+                    self.emit_with_u16(OpCode::GetProperty, constant);
+                    self.emit(OpCode::Swap);
+                    self.emit(OpCode::Over);
+                    self.step(operator);
+                    self.at(name);
+                    self.emit_with_u16(OpCode::SetProperty, constant);
+                    self.emit(OpCode::Pop);
+                }
+                _ => panic!("the parser only steps variables and properties"),
+            },
+            Expr::Super(keyword, method, _) => {
+                self.at(keyword);
+                self.named_variable("this", false);
+                self.named_variable("super", false);
+                self.at(method);
+                let constant = self.string_constant(&method.lexeme);
+                self.emit_with_u16(OpCode::GetSuper, constant);
+            }
+            Expr::This(keyword, _) => {
+                self.at(keyword);
+                self.named_variable("this", false);
+            }
+            Expr::Unary(operator, right) => {
+                self.expression(right);
+                self.at(operator);
+                match operator.token_type {
+                    TokenType::Bang => self.emit(OpCode::Not),
+                    _ => self.emit(OpCode::Negate),
+                }
+            }
+            Expr::Variable(name, _) => self.variable(name, false),
+        }
+    }
+
+    // Adds or subtracts one from the value on the stack
+    fn step(&mut self, operator: &'a Token) {
+        let one = self.make_constant(Constant::Number(1.0));
+        self.emit_with_u16(OpCode::Constant, one);
+        self.at(operator);
+        self.emit(binary_op(&operator.token_type));
+    }
+
+    fn variable(&mut self, name: &'a Token, assign: bool) {
+        self.at(name);
+        self.named_variable(&name.lexeme, assign);
+    }
+
+    // Emits a read, or with assign a write of the value on the stack, of
+    // a local, an upvalue or a global, in that order
+    fn named_variable(&mut self, name: &'a str, assign: bool) {
+        let depth = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(depth, name) {
+            let op = if assign { OpCode::SetLocal } else { OpCode::GetLocal };
+            self.emit(op);
+            self.emit_byte(slot);
+        } else if let Some(index) = self.resolve_upvalue(depth, name) {
+            let op = if assign { OpCode::SetUpvalue } else { OpCode::GetUpvalue };
+            self.emit(op);
+            self.emit_byte(index);
+        } else {
+            let slot = self.global_slot(name);
+            let op = if assign { OpCode::SetGlobal } else { OpCode::GetGlobal };
+            self.emit_with_u16(op, slot);
+        }
+    }
+
+    fn resolve_local(&self, function: usize, name: &str) -> Option<u8> {
+        self.functions[function]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, function: usize, name: &str) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            self.functions[function - 1].locals[slot as usize].captured = true;
+            return self.add_upvalue(function, true, slot);
+        }
+        let index = self.resolve_upvalue(function - 1, name)?;
+        self.add_upvalue(function, false, index)
+    }
+
+    fn add_upvalue(&mut self, function: usize, is_local: bool, index: u8) -> Option<u8> {
+        let upvalue = Upvalue { is_local, index };
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Some(existing as u8);
+        }
+        if upvalues.len() == u8::MAX as usize + 1 {
+            self.error("Too many closure variables in function.");
+            return Some(0);
+        }
+        upvalues.push(upvalue);
+        Some((upvalues.len() - 1) as u8)
+    }
+
+    fn global_slot(&mut self, name: &'a str) -> u16 {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
+        }
+        if self.globals.len() > u16::MAX as usize {
+            self.error("Too many global variables.");
+            return 0;
+        }
+        let slot = self.globals.len() as u16;
+        self.globals.push(Rc::from(name));
+        self.global_slots.insert(name, slot);
+        slot
+    }
+
+    // Makes a local for a declaration inside a scope, or returns the
+    // global slot for one at the top level
+    fn declare(&mut self, name: &'a Token) -> Option<u16> {
+        if self.current().scope_depth == 0 {
+            return Some(self.global_slot(&name.lexeme));
+        }
+        self.add_local(&name.lexeme);
+        None
+    }
+
+    // Locals need no instruction; the value is already in their slot
+    fn define(&mut self, global: Option<u16>) {
+        if let Some(slot) = global {
+            self.emit_with_u16(OpCode::DefineGlobal, slot);
+        }
+    }
+
+    fn add_local(&mut self, name: &'a str) {
+        let state = self.current();
+        if state.locals.len() == u8::MAX as usize + 1 {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        let depth = state.scope_depth;
+        state.locals.push(Local {
+            name,
+            depth,
+            captured: false,
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+        loop {
+            let state = self.current();
+            let Some(local) = state.locals.last() else {
+                break;
+            };
+            if local.depth <= state.scope_depth {
+                break;
+            }
+            let op = if local.captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            state.locals.pop();
+            self.emit(op);
+        }
+    }
+
+    fn current(&mut self) -> &mut FunctionState<'a> {
+        self.functions
+            .last_mut()
+            .expect("the script is compiled inside a function")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    fn at(&mut self, token: &'a Token) {
+        self.token = Some(token);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let token = self.token;
+        self.chunk().write(byte, token);
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit(op);
+        for byte in operand.to_be_bytes() {
+            self.emit_byte(byte);
+        }
+    }
+
+    // Initializers return 'this'; everything else returns nil by default
+    fn emit_return(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+
+    // Returns the offset of the operand to patch
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_with_u16(op, u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, operand: usize) {
+        let distance = self.chunk().code.len() - operand - 2;
+        let Ok(distance) = u16::try_from(distance) else {
+            self.error("Too much code to jump over.");
+            return;
+        };
+        self.chunk().code[operand..operand + 2].copy_from_slice(&distance.to_be_bytes());
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        let distance = self.chunk().code.len() + 3 - loop_start;
+        let Ok(distance) = u16::try_from(distance) else {
+            self.error("Loop body too large.");
+            return;
+        };
+        self.emit_with_u16(OpCode::Loop, distance);
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> u16 {
+        let constants = &mut self.chunk().constants;
+        if constants.len() > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constants.push(constant);
+        (constants.len() - 1) as u16
+    }
+
+    // Reuses an existing constant for the same string, as names repeat a lot
+    fn string_constant(&mut self, s: &'a str) -> u16 {
+        if let Some(&index) = self.current().strings.get(s) {
+            return index;
+        }
+        let index = self.make_constant(Constant::String(Rc::from(s)));
+        self.current().strings.insert(s, index);
+        index
+    }
+
+    // Each limit is reported once, where it's first hit
+    fn error(&mut self, message: &str) {
+        if self.errors.iter().any(|e| e.err_msg == message) {
+            return;
+        }
+        if let Some(token) = self.token {
+            self.errors.push(CompileErr {
+                err_token: token.clone(),
+                err_msg: String::from(message),
+            });
+        }
+    }
+}
+
+fn binary_op(token_type: &TokenType) -> OpCode {
+    match token_type {
+        TokenType::Plus => OpCode::Add,
+        TokenType::Minus => OpCode::Subtract,
+        TokenType::Star => OpCode::Multiply,
+        TokenType::Slash => OpCode::Divide,
+        TokenType::Greater => OpCode::Greater,
+        TokenType::GreaterEqual => OpCode::GreaterEqual,
+        TokenType::Less => OpCode::Less,
+        TokenType::LessEqual => OpCode::LessEqual,
+        TokenType::BangEqual => OpCode::NotEqual,
+        _ => OpCode::Equal,
+    }
+}
@@ -0,0 +1,796 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs::read_to_string;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::debugger::{Debugger, Frontend, Resume, Session, StopReason};
+use crate::interpreter::{statement_lines, Interpreter};
+use crate::json::Json;
+use crate::natives;
+use crate::rox_type::RoxType;
+use crate::rpc::{read_message, write_message};
+use crate::Rox;
+
+const THREAD_ID: f64 = 1.0;
+
+// Debug Adapter Protocol server for a single launched script. Requests are
+// read on a separate thread so pause and breakpoint changes are seen while
+// the script runs.
+pub fn serve<R, W>(input: R, output: W) -> io::Result<i32>
+where
+    R: BufRead + Send + 'static,
+    W: Write + 'static,
+{
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if let Ok(message) = Json::parse(&message) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let connection = Rc::new(RefCell::new(Connection {
+        output: Box::new(output),
+        seq: 0,
+    }));
+
+    // Shared with the frontend, which answers requests while stopped
+    let requests = Rc::new(requests);
+
+    let Some(launch) = configure(&connection, &requests)? else {
+        return Ok(0);
+    };
+    let exit_code = launch.run(&connection, &requests);
+    finish(&connection, &requests, exit_code)?;
+    Ok(0)
+}
+
+struct Launch {
+    program: String,
+    args: Vec<String>,
+    stop_on_entry: bool,
+    session: Session,
+}
+
+// Handles requests up to configurationDone. Returns None if the client
+// disconnects first.
+fn configure(
+    connection: &Rc<RefCell<Connection>>,
+    requests: &Receiver<Json>,
+) -> io::Result<Option<Launch>> {
+    let mut launch = Launch {
+        program: String::new(),
+        args: Vec::new(),
+        stop_on_entry: false,
+        session: Session::default(),
+    };
+    for request in requests.iter() {
+        let mut connection = connection.borrow_mut();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        match command(&request) {
+            "initialize" => {
+                connection.respond(&request, capabilities())?;
+                connection.event("initialized", Json::Null)?;
+            }
+            "launch" => {
+                let program = arguments.get("program").and_then(Json::as_str);
+                let Some(program) = program else {
+                    connection.fail(&request, "launch needs a program")?;
+                    continue;
+                };
+                launch.program = String::from(program);
+                launch.stop_on_entry = arguments
+                    .get("stopOnEntry")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false);
+                launch.args = arguments
+                    .get("args")
+                    .and_then(Json::as_array)
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(Json::as_str)
+                    .map(String::from)
+                    .collect();
+                connection.respond(&request, Json::Null)?;
+            }
+            "setBreakpoints" => {
+                let body = set_breakpoints(&mut launch.session, &arguments);
+                connection.respond(&request, body)?;
+            }
+            "threads" => connection.respond(&request, threads())?,
+            "configurationDone" => {
+                connection.respond(&request, Json::Null)?;
+                return Ok(Some(launch));
+            }
+            "disconnect" | "terminate" => {
+                connection.respond(&request, Json::Null)?;
+                return Ok(None);
+            }
+            "setExceptionBreakpoints" => connection.respond(&request, Json::Null)?,
+            other => connection.fail(&request, &format!("Unsupported request '{}'", other))?,
+        }
+    }
+    Ok(None)
+}
+
+impl Launch {
+    // Runs the script under the debugger and returns its exit code
+    fn run(self, connection: &Rc<RefCell<Connection>>, requests: &Rc<Receiver<Json>>) -> i32 {
+        let code = match read_to_string(&self.program) {
+            Ok(code) => code,
+            Err(e) => {
+                let message = format!("Could not read {}: {}\n", self.program, e);
+                let _ = connection.borrow_mut().output("stderr", &message);
+                return 74;
+            }
+        };
+        let statements = match Rox::compile(&code) {
+            Ok(statements) => statements,
+            Err(e) => {
+                let _ = connection.borrow_mut().output("stderr", &format!("{}\n", e));
+                return 65;
+            }
+        };
+
+        let mut interpreter = Interpreter::new();
+        natives::define_args(&mut interpreter, self.args);
+        interpreter.set_output(Box::new(Output {
+            connection: connection.clone(),
+            buffer: String::new(),
+        }));
+        let frontend = DapFrontend {
+            connection: connection.clone(),
+            requests: requests.clone(),
+            program: self.program,
+            variables: Vec::new(),
+        };
+        interpreter.set_debugger(Box::new(Debugger::new(
+            frontend,
+            self.session,
+            self.stop_on_entry,
+        )));
+
+        match interpreter.interpret(statements) {
+            Ok(()) => 0,
+            Err(e) => {
+                let _ = connection.borrow_mut().output("stderr", &format!("{}\n", e));
+                70
+            }
+        }
+    }
+}
+
+// Reports that the script has ended and answers requests until the client
+// disconnects
+fn finish(
+    connection: &Rc<RefCell<Connection>>,
+    requests: &Receiver<Json>,
+    exit_code: i32,
+) -> io::Result<()> {
+    let mut connection = connection.borrow_mut();
+    connection.event(
+        "exited",
+        Json::object(vec![("exitCode", Json::Number(exit_code as f64))]),
+    )?;
+    connection.event("terminated", Json::Null)?;
+    for request in requests.iter() {
+        match command(&request) {
+            "disconnect" | "terminate" => {
+                connection.respond(&request, Json::Null)?;
+                break;
+            }
+            "threads" => connection.respond(&request, threads())?,
+            _ => connection.fail(&request, "The program has ended")?,
+        }
+    }
+    Ok(())
+}
+
+struct Connection {
+    output: Box<dyn Write>,
+    seq: u64,
+}
+
+impl Connection {
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq", Json::Number(self.seq as f64)));
+        write_message(&mut self.output, &Json::object(fields))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::Bool(true)),
+            ("command", Json::string(command(request))),
+            ("body", body),
+        ])
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::Bool(false)),
+            ("command", Json::string(command(request))),
+            ("message", Json::string(message)),
+        ])
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("event")),
+            ("event", Json::string(event)),
+            ("body", body),
+        ])
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event(
+            "output",
+            Json::object(vec![
+                ("category", Json::string(category)),
+                ("output", Json::string(text)),
+            ]),
+        )
+    }
+}
+
+// Sends the script's print output to the client a line at a time, since
+// stdout carries the protocol
+struct Output {
+    connection: Rc<RefCell<Connection>>,
+    buffer: String,
+}
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.push_str(&String::from_utf8_lossy(bytes));
+        if let Some(end) = self.buffer.rfind('\n') {
+            let lines = self.buffer.drain(..=end).collect::<String>();
+            self.connection.borrow_mut().output("stdout", &lines)?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let rest = std::mem::take(&mut self.buffer);
+            self.connection.borrow_mut().output("stdout", &rest)?;
+        }
+        Ok(())
+    }
+}
+
+struct DapFrontend {
+    connection: Rc<RefCell<Connection>>,
+    requests: Rc<Receiver<Json>>,
+    program: String,
+    // Containers handed out as variablesReference n + 1 during a stop
+    variables: Vec<Vec<(String, RoxType)>>,
+}
+
+impl Frontend for DapFrontend {
+    fn stopped(
+        &mut self,
+        session: &mut Session,
+        interpreter: &Interpreter,
+        reason: StopReason,
+        _line: u32,
+    ) -> Resume {
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+            StopReason::Pause => "pause",
+        };
+        let stopped = Json::object(vec![
+            ("reason", Json::string(reason)),
+            ("threadId", Json::Number(THREAD_ID)),
+            ("allThreadsStopped", Json::Bool(true)),
+        ]);
+        self.io(|connection| connection.event("stopped", stopped));
+
+        loop {
+            let Ok(request) = self.requests.recv() else {
+                // The client went away
+                std::process::exit(0);
+            };
+            if let Some(resume) = self.handle(&request, session, Some(interpreter)) {
+                self.variables.clear();
+                return resume;
+            }
+        }
+    }
+
+    fn running(&mut self, session: &mut Session) {
+        while let Ok(request) = self.requests.try_recv() {
+            self.handle(&request, session, None);
+        }
+    }
+}
+
+impl DapFrontend {
+    // Answers a request, returning how to resume if it was a step or
+    // continue. The interpreter is only there while stopped.
+    fn handle(
+        &mut self,
+        request: &Json,
+        session: &mut Session,
+        interpreter: Option<&Interpreter>,
+    ) -> Option<Resume> {
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let resume = match command(request) {
+            "continue" => Some(Resume::Continue),
+            "next" => Some(Resume::StepOver),
+            "stepIn" => Some(Resume::StepIn),
+            "stepOut" => Some(Resume::StepOut),
+            _ => None,
+        };
+        if let Some(resume) = resume {
+            let body = Json::object(vec![("allThreadsContinued", Json::Bool(true))]);
+            self.io(|connection| connection.respond(request, body));
+            // A step while running just carries on
+            return interpreter.map(|_| resume);
+        }
+
+        let body = match (command(request), interpreter) {
+            ("setBreakpoints", _) => Ok(set_breakpoints(session, &arguments)),
+            ("threads", _) => Ok(threads()),
+            ("pause", _) => {
+                session.pause.store(true, Ordering::SeqCst);
+                Ok(Json::Null)
+            }
+            ("disconnect" | "terminate", _) => {
+                self.io(|connection| connection.respond(request, Json::Null));
+                std::process::exit(0);
+            }
+            ("stackTrace", Some(interpreter)) => Ok(self.stack_trace(interpreter)),
+            ("scopes", Some(interpreter)) => Ok(self.scopes(interpreter, &arguments)),
+            ("variables", Some(_)) => Ok(self.variables(&arguments)),
+            ("evaluate", Some(interpreter)) => evaluate(interpreter, &arguments),
+            ("stackTrace" | "scopes" | "variables" | "evaluate", None) => {
+                Err(String::from("The program is running"))
+            }
+            (other, _) => Err(format!("Unsupported request '{}'", other)),
+        };
+        match body {
+            Ok(body) => self.io(|connection| connection.respond(request, body)),
+            Err(message) => self.io(|connection| connection.fail(request, &message)),
+        }
+        None
+    }
+
+    fn stack_trace(&self, interpreter: &Interpreter) -> Json {
+        let name = Path::new(&self.program)
+            .file_name()
+            .map_or(self.program.clone(), |name| name.to_string_lossy().into_owned());
+        let source = Json::object(vec![
+            ("name", Json::string(&name)),
+            ("path", Json::string(&self.program)),
+        ]);
+        let frames = interpreter
+            .call_stack()
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                Json::object(vec![
+                    ("id", Json::Number(id as f64)),
+                    ("name", Json::string(&frame.function)),
+                    ("source", source.clone()),
+                    ("line", Json::Number(frame.line as f64)),
+                    ("column", Json::Number(1.0)),
+                ])
+            })
+            .collect::<Vec<Json>>();
+        Json::object(vec![
+            ("totalFrames", Json::Number(frames.len() as f64)),
+            ("stackFrames", Json::Array(frames)),
+        ])
+    }
+
+    fn scopes(&mut self, interpreter: &Interpreter, arguments: &Json) -> Json {
+        let frame = arguments.get("frameId").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        let scopes = interpreter.frame_scopes(frame);
+        let count = scopes.len();
+        let scopes = scopes
+            .into_iter()
+            .enumerate()
+            .map(|(i, variables)| {
+                let name = match i {
+                    _ if i + 1 == count => String::from("Globals"),
+                    0 => String::from("Locals"),
+                    _ => format!("Enclosing {}", i),
+                };
+                Json::object(vec![
+                    ("name", Json::string(&name)),
+                    ("variablesReference", Json::Number(self.reference(variables))),
+                    ("expensive", Json::Bool(false)),
+                ])
+            })
+            .collect();
+        Json::object(vec![("scopes", Json::Array(scopes))])
+    }
+
+    fn variables(&mut self, arguments: &Json) -> Json {
+        let reference = arguments
+            .get("variablesReference")
+            .and_then(Json::as_f64)
+            .unwrap_or(0.0) as usize;
+        let container = match reference.checked_sub(1) {
+            Some(index) => self.variables.get(index).cloned().unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let variables = container
+            .into_iter()
+            .map(|(name, value)| {
+                let children = match &value {
+                    RoxType::Instance(instance) => self.reference(instance.borrow().fields()),
+                    _ => 0.0,
+                };
+                Json::object(vec![
+                    ("name", Json::string(&name)),
+                    ("value", Json::string(&value.to_string())),
+                    ("type", Json::string(type_name(&value))),
+                    ("variablesReference", Json::Number(children)),
+                ])
+            })
+            .collect();
+        Json::object(vec![("variables", Json::Array(variables))])
+    }
+
+    fn reference(&mut self, variables: Vec<(String, RoxType)>) -> f64 {
+        self.variables.push(variables);
+        self.variables.len() as f64
+    }
+
+    // Writes to the client; if it can't be reached the session is over
+    fn io<F>(&self, send: F)
+    where
+        F: FnOnce(&mut Connection) -> io::Result<()>,
+    {
+        if send(&mut self.connection.borrow_mut()).is_err() {
+            std::process::exit(0);
+        }
+    }
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").and_then(Json::as_str).unwrap_or("")
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", Json::Bool(true)),
+        ("supportsEvaluateForHovers", Json::Bool(true)),
+    ])
+}
+
+fn threads() -> Json {
+    Json::object(vec![(
+        "threads",
+        Json::Array(vec![Json::object(vec![
+            ("id", Json::Number(THREAD_ID)),
+            ("name", Json::string("main")),
+        ])]),
+    )])
+}
+
+// The script is a single file, so the source in the request is taken to be
+// that file. Breakpoints on lines where no statement starts can never be hit,
+// so they're reported unverified.
+fn set_breakpoints(session: &mut Session, arguments: &Json) -> Json {
+    session.breakpoints = arguments
+        .get("breakpoints")
+        .and_then(Json::as_array)
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_f64))
+        .map(|line| line as u32)
+        .collect();
+    let lines = source_statement_lines(arguments);
+    let breakpoints = session
+        .breakpoints
+        .iter()
+        .map(|&line| {
+            let unverified = match &lines {
+                Ok(lines) if lines.contains(&line) => None,
+                Ok(_) => Some("No statement starts on this line"),
+                Err(message) => Some(message.as_str()),
+            };
+            let mut fields = vec![
+                ("verified", Json::Bool(unverified.is_none())),
+                ("line", Json::Number(line as f64)),
+            ];
+            if let Some(message) = unverified {
+                fields.push(("message", Json::string(message)));
+            }
+            Json::object(fields)
+        })
+        .collect();
+    Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+}
+
+// The lines a statement starts on in the source of a setBreakpoints request
+fn source_statement_lines(arguments: &Json) -> Result<BTreeSet<u32>, String> {
+    let path = arguments
+        .get("source")
+        .and_then(|source| source.get("path"))
+        .and_then(Json::as_str)
+        .ok_or_else(|| String::from("The breakpoints have no source path"))?;
+    let code = read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let statements =
+        Rox::compile(&code).map_err(|_| String::from("The script has errors"))?;
+    Ok(statement_lines(&statements))
+}
+
+// Looks a variable up in the scopes of a frame; general expressions aren't
+// evaluated while stopped
+fn evaluate(interpreter: &Interpreter, arguments: &Json) -> Result<Json, String> {
+    let name = arguments
+        .get("expression")
+        .and_then(Json::as_str)
+        .unwrap_or("")
+        .trim();
+    let frame = arguments.get("frameId").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let value = interpreter
+        .frame_scopes(frame)
+        .into_iter()
+        .flatten()
+        .find(|(variable, _)| variable == name)
+        .map(|(_, value)| value)
+        .ok_or_else(|| format!("No variable named '{}'", name))?;
+    Ok(Json::object(vec![
+        ("result", Json::string(&value.to_string())),
+        ("type", Json::string(type_name(&value))),
+        ("variablesReference", Json::Number(0.0)),
+    ]))
+}
+
+fn type_name(value: &RoxType) -> &'static str {
+    match value {
+        RoxType::String(_) => "string",
+        RoxType::Number(_) => "number",
+        RoxType::Nil => "nil",
+        RoxType::Boolean(_) => "boolean",
+        RoxType::Callable(_) => "function",
+        RoxType::Class(_) => "class",
+        RoxType::Instance(_) => "instance",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::io::{pipe, BufReader, PipeReader, PipeWriter};
+    use std::thread::JoinHandle;
+
+    const SCRIPT: &str = "fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var x = 1; var y = 2;
+var total = add(x, y);
+print total;
+";
+
+    // Drives a server on another thread the way an editor would
+    struct Client {
+        input: Option<PipeWriter>,
+        output: BufReader<PipeReader>,
+        server: Option<JoinHandle<io::Result<i32>>>,
+        // Events that arrived while waiting for something else
+        events: VecDeque<Json>,
+        seq: f64,
+        program: String,
+    }
+
+    impl Client {
+        fn launch(name: &str) -> Client {
+            let name = format!("rox-dap-{}-{}.lox", std::process::id(), name);
+            let program = std::env::temp_dir().join(name);
+            fs::write(&program, SCRIPT).unwrap();
+            let (server_input, input) = pipe().unwrap();
+            let (output, server_output) = pipe().unwrap();
+            let server = thread::spawn(move || serve(BufReader::new(server_input), server_output));
+            let mut client = Client {
+                input: Some(input),
+                output: BufReader::new(output),
+                server: Some(server),
+                events: VecDeque::new(),
+                seq: 0.0,
+                program: program.to_string_lossy().into_owned(),
+            };
+            client.body("initialize", Json::Null);
+            client.event("initialized");
+            let program = Json::string(&client.program);
+            client.body("launch", Json::object(vec![("program", program)]));
+            client
+        }
+
+        // Sends a request and waits for its response
+        fn request(&mut self, command: &str, arguments: Json) -> Json {
+            self.seq += 1.0;
+            let request = Json::object(vec![
+                ("seq", Json::Number(self.seq)),
+                ("type", Json::string("request")),
+                ("command", Json::string(command)),
+                ("arguments", arguments),
+            ]);
+            write_message(self.input.as_mut().unwrap(), &request).unwrap();
+            loop {
+                let message = self.receive();
+                if message.get("request_seq") == Some(&Json::Number(self.seq)) {
+                    return message;
+                }
+                self.events.push_back(message);
+            }
+        }
+
+        // The body of a request's response, which has to succeed
+        fn body(&mut self, command: &str, arguments: Json) -> Json {
+            let response = self.request(command, arguments);
+            assert_eq!(response.get("success"), Some(&Json::Bool(true)), "{}", response);
+            response.get("body").cloned().unwrap()
+        }
+
+        // Waits for an event and returns its body
+        fn event(&mut self, event: &str) -> Json {
+            let is_it = |message: &Json| message.get("event").and_then(Json::as_str) == Some(event);
+            let message = match self.events.iter().position(is_it) {
+                Some(index) => self.events.remove(index).unwrap(),
+                None => loop {
+                    let message = self.receive();
+                    if is_it(&message) {
+                        break message;
+                    }
+                    self.events.push_back(message);
+                },
+            };
+            message.get("body").cloned().unwrap()
+        }
+
+        fn receive(&mut self) -> Json {
+            let message = read_message(&mut self.output).unwrap().expect("the server hung up");
+            Json::parse(&message).unwrap()
+        }
+
+        fn set_breakpoints(&mut self, path: &str, lines: &[u32]) -> Vec<Json> {
+            let breakpoints = lines
+                .iter()
+                .map(|&line| Json::object(vec![("line", Json::Number(line as f64))]))
+                .collect();
+            let arguments = Json::object(vec![
+                ("source", Json::object(vec![("path", Json::string(path))])),
+                ("breakpoints", Json::Array(breakpoints)),
+            ]);
+            let body = self.body("setBreakpoints", arguments);
+            body.get("breakpoints").and_then(Json::as_array).unwrap().to_vec()
+        }
+
+        fn stopped(&mut self) -> String {
+            let body = self.event("stopped");
+            String::from(body.get("reason").and_then(Json::as_str).unwrap())
+        }
+
+        // The function and line of each frame, innermost first
+        fn stack(&mut self) -> Vec<(String, f64)> {
+            let body = self.body("stackTrace", Json::object(vec![("threadId", Json::Number(1.0))]));
+            let frames = body.get("stackFrames").and_then(Json::as_array).unwrap();
+            frames
+                .iter()
+                .map(|frame| {
+                    let name = frame.get("name").and_then(Json::as_str).unwrap();
+                    (String::from(name), frame.get("line").and_then(Json::as_f64).unwrap())
+                })
+                .collect()
+        }
+
+        // The names and values in the innermost scope of the top frame
+        fn locals(&mut self) -> Vec<(String, String)> {
+            let body = self.body("scopes", Json::object(vec![("frameId", Json::Number(0.0))]));
+            let scope = &body.get("scopes").and_then(Json::as_array).unwrap()[0];
+            assert_eq!(scope.get("name").and_then(Json::as_str), Some("Locals"));
+            let reference = scope.get("variablesReference").cloned().unwrap();
+            let body = self.body("variables", Json::object(vec![("variablesReference", reference)]));
+            let variables = body.get("variables").and_then(Json::as_array).unwrap();
+            variables
+                .iter()
+                .map(|variable| {
+                    let field = |key| String::from(variable.get(key).and_then(Json::as_str).unwrap());
+                    (field("name"), field("value"))
+                })
+                .collect()
+        }
+
+        fn disconnect(mut self) {
+            self.body("disconnect", Json::Null);
+            drop(self.input.take());
+            let server = self.server.take().unwrap();
+            assert_eq!(server.join().unwrap().unwrap(), 0);
+            let _ = fs::remove_file(&self.program);
+        }
+    }
+
+    impl Drop for Client {
+        // Closing the input while the script is stopped exits the process,
+        // which would hide the assertion that failed, so the server is left
+        // waiting instead
+        fn drop(&mut self) {
+            std::mem::forget(self.input.take());
+        }
+    }
+
+    fn frame(function: &str, line: f64) -> (String, f64) {
+        (String::from(function), line)
+    }
+
+    fn variable(name: &str, value: &str) -> (String, String) {
+        (String::from(name), String::from(value))
+    }
+
+    #[test]
+    fn verifies_breakpoints_on_statement_lines() {
+        let mut client = Client::launch("verify");
+        let program = client.program.clone();
+        let breakpoints = client.set_breakpoints(&program, &[2, 4, 5, 9]);
+        let verified = breakpoints
+            .iter()
+            .map(|breakpoint| breakpoint.get("verified").and_then(Json::as_bool).unwrap())
+            .collect::<Vec<bool>>();
+        assert_eq!(verified, [true, false, true, false]);
+        assert_eq!(
+            breakpoints[1].get("message").and_then(Json::as_str),
+            Some("No statement starts on this line")
+        );
+
+        let missing = client.set_breakpoints("/no/such/script.lox", &[2]);
+        assert_eq!(missing[0].get("verified"), Some(&Json::Bool(false)));
+        let message = missing[0].get("message").and_then(Json::as_str).unwrap();
+        assert!(message.starts_with("Could not read /no/such/script.lox"), "{}", message);
+        client.disconnect();
+    }
+
+    #[test]
+    fn steps_through_a_session() {
+        let mut client = Client::launch("session");
+        let program = client.program.clone();
+        client.set_breakpoints(&program, &[6]);
+        client.body("configurationDone", Json::Null);
+        assert_eq!(client.stopped(), "breakpoint");
+        assert_eq!(client.stack(), [frame("script", 6.0)]);
+
+        client.body("stepIn", Json::Null);
+        assert_eq!(client.stopped(), "step");
+        assert_eq!(client.stack(), [frame("add()", 2.0), frame("script", 6.0)]);
+        assert_eq!(client.locals(), [variable("a", "1"), variable("b", "2")]);
+
+        client.body("next", Json::Null);
+        assert_eq!(client.stopped(), "step");
+        assert_eq!(client.stack()[0], frame("add()", 3.0));
+        assert!(client.locals().contains(&variable("sum", "3")));
+
+        client.body("stepOut", Json::Null);
+        assert_eq!(client.stopped(), "step");
+        assert_eq!(client.stack(), [frame("script", 7.0)]);
+
+        client.body("continue", Json::Null);
+        let output = client.event("output");
+        assert_eq!(output.get("output").and_then(Json::as_str), Some("3\n"));
+        let exited = client.event("exited");
+        assert_eq!(exited.get("exitCode"), Some(&Json::Number(0.0)));
+        client.event("terminated");
+        client.disconnect();
+    }
+}
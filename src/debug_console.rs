@@ -0,0 +1,153 @@
+use std::process;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use rox::debugger::{Frontend, Resume, Session, StopReason};
+use rox::interpreter::Interpreter;
+
+const PROMPT: &str = "(rox) ";
+
+const HELP: &str = "Commands:
+  c, continue     Run to the next breakpoint
+  s, step         Step to the next statement, into calls
+  n, next         Step to the next statement, over calls
+  f, finish       Run until the current function returns
+  b, break N      Set a breakpoint on line N
+  d, delete [N]   Remove the breakpoint on line N, or all of them
+  bt, backtrace   Show the call stack
+  p, print NAME   Show the value of a variable
+  vars, locals    Show the variables in every scope
+  l, list         Show the source around the current line
+  q, quit         Stop the program and exit
+  h, help         Show this message
+
+An empty line repeats the last command. Ctrl-C while the program runs stops
+it at the next statement.";
+
+// gdb-style prompt shown whenever the program stops
+pub struct Console {
+    editor: DefaultEditor,
+    lines: Vec<String>,
+    last_command: String,
+}
+
+impl Console {
+    pub fn new(source: &str) -> Result<Console, ReadlineError> {
+        Ok(Console {
+            editor: DefaultEditor::new()?,
+            lines: source.lines().map(String::from).collect(),
+            last_command: String::new(),
+        })
+    }
+
+    fn list(&self, line: u32, around: u32, session: &Session) {
+        let first = line.saturating_sub(around).max(1);
+        let last = (line + around).min(self.lines.len() as u32);
+        for n in first..=last {
+            let marker = if n == line { ">" } else { " " };
+            let breakpoint = if session.breakpoints.contains(&n) { "*" } else { " " };
+            println!("{}{}{:>4} | {}", marker, breakpoint, n, self.lines[n as usize - 1]);
+        }
+    }
+}
+
+impl Frontend for Console {
+    fn stopped(
+        &mut self,
+        session: &mut Session,
+        interpreter: &Interpreter,
+        reason: StopReason,
+        line: u32,
+    ) -> Resume {
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+            StopReason::Pause => "pause",
+        };
+        println!("Stopped at {}, line {}", reason, line);
+        self.list(line, 0, session);
+
+        loop {
+            let input = match self.editor.readline(PROMPT) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(_) => process::exit(0),
+            };
+            let input = if input.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                let _ = self.editor.add_history_entry(input.trim());
+                input.trim().to_string()
+            };
+            self.last_command = input.clone();
+
+            let mut words = input.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let argument = words.next();
+            match command {
+                "" => (),
+                "c" | "continue" => return Resume::Continue,
+                "s" | "step" => return Resume::StepIn,
+                "n" | "next" => return Resume::StepOver,
+                "f" | "finish" => return Resume::StepOut,
+                "b" | "break" => match argument.and_then(|n| n.parse::<u32>().ok()) {
+                    Some(n) if n >= 1 && n as usize <= self.lines.len() => {
+                        session.breakpoints.insert(n);
+                        println!("Breakpoint on line {}", n);
+                    }
+                    _ => println!("Usage: break LINE, with a line of the script"),
+                },
+                "d" | "delete" => match argument.map(|n| n.parse::<u32>()) {
+                    None => {
+                        session.breakpoints.clear();
+                        println!("Removed all breakpoints");
+                    }
+                    Some(Ok(n)) if session.breakpoints.remove(&n) => {
+                        println!("Removed the breakpoint on line {}", n)
+                    }
+                    Some(_) => println!("No breakpoint on that line"),
+                },
+                "bt" | "backtrace" => {
+                    for (i, frame) in interpreter.call_stack().iter().enumerate() {
+                        println!("#{} {} at line {}", i, frame.function, frame.line);
+                    }
+                }
+                "p" | "print" => {
+                    let Some(name) = argument else {
+                        println!("Usage: print NAME");
+                        continue;
+                    };
+                    let value = interpreter
+                        .frame_scopes(0)
+                        .into_iter()
+                        .flatten()
+                        .find(|(variable, _)| variable == name);
+                    match value {
+                        Some((_, value)) => println!("{} = {}", name, value),
+                        None => println!("No variable named '{}'", name),
+                    }
+                }
+                "vars" | "locals" => {
+                    let scopes = interpreter.frame_scopes(0);
+                    let count = scopes.len();
+                    for (i, scope) in scopes.into_iter().enumerate() {
+                        match i {
+                            _ if i + 1 == count => println!("Globals:"),
+                            0 => println!("Locals:"),
+                            _ => println!("Enclosing {}:", i),
+                        }
+                        for (name, value) in scope {
+                            println!("  {} = {}", name, value);
+                        }
+                    }
+                }
+                "l" | "list" => self.list(line, 5, session),
+                "q" | "quit" => process::exit(0),
+                "h" | "help" => println!("{}", HELP),
+                other => println!("Unknown command '{}'. Try 'help'.", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::interpreter::Interpreter;
+
+// Called by the interpreter before each statement that starts on a line.
+// The program is paused until it returns.
+pub trait DebugHook {
+    fn on_statement(&mut self, interpreter: &Interpreter, line: u32);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+    Pause,
+}
+
+// How to carry on after a stop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    Continue,
+    StepIn,
+    StepOver,
+    StepOut,
+}
+
+// State a frontend can change while the program runs or is stopped
+#[derive(Default)]
+pub struct Session {
+    pub breakpoints: BTreeSet<u32>,
+    // Set from another thread or a signal handler to stop at the next
+    // statement
+    pub pause: Arc<AtomicBool>,
+}
+
+// The user-facing side of a debugger: a console, an editor over DAP
+pub trait Frontend {
+    // The program has stopped; blocks until the user says how to go on
+    fn stopped(
+        &mut self,
+        session: &mut Session,
+        interpreter: &Interpreter,
+        reason: StopReason,
+        line: u32,
+    ) -> Resume;
+
+    // Called before every statement while running, to pick up requests that
+    // arrive in the meantime
+    fn running(&mut self, _session: &mut Session) {}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Run,
+    StepIn,
+    // Stop at the next statement at or above this call depth
+    StepOver(usize),
+    // Stop at the next statement above this call depth
+    StepOut(usize),
+}
+
+// Decides where to stop: line breakpoints, stepping and pause requests
+pub struct Debugger<F: Frontend> {
+    frontend: F,
+    session: Session,
+    mode: Mode,
+    stop_on_entry: bool,
+    // Call depth and line of the last statement, so a breakpoint fires once
+    // when its line is reached rather than for every statement on it
+    last: Option<(usize, u32)>,
+}
+
+impl<F: Frontend> Debugger<F> {
+    pub fn new(frontend: F, session: Session, stop_on_entry: bool) -> Debugger<F> {
+        Debugger {
+            frontend,
+            session,
+            mode: Mode::Run,
+            stop_on_entry,
+            last: None,
+        }
+    }
+}
+
+impl<F: Frontend> DebugHook for Debugger<F> {
+    fn on_statement(&mut self, interpreter: &Interpreter, line: u32) {
+        self.frontend.running(&mut self.session);
+
+        let depth = interpreter.call_depth();
+        let entering = self.last != Some((depth, line));
+        self.last = Some((depth, line));
+
+        let reason = if std::mem::take(&mut self.stop_on_entry) {
+            Some(StopReason::Entry)
+        } else if self.session.pause.swap(false, Ordering::SeqCst) {
+            Some(StopReason::Pause)
+        } else if entering && self.session.breakpoints.contains(&line) {
+            Some(StopReason::Breakpoint)
+        } else {
+            match self.mode {
+                Mode::Run => None,
+                Mode::StepIn => Some(StopReason::Step),
+                Mode::StepOver(target) if depth <= target => Some(StopReason::Step),
+                Mode::StepOut(target) if depth < target => Some(StopReason::Step),
+                Mode::StepOver(_) | Mode::StepOut(_) => None,
+            }
+        };
+        let Some(reason) = reason else {
+            return;
+        };
+
+        let resume = self
+            .frontend
+            .stopped(&mut self.session, interpreter, reason, line);
+        self.mode = match resume {
+            Resume::Continue => Mode::Run,
+            Resume::StepIn => Mode::StepIn,
+            Resume::StepOver => Mode::StepOver(depth),
+            Resume::StepOut => Mode::StepOut(depth),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::rc::Rc;
+
+    use crate::Rox;
+
+    const SCRIPT: &str = "fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var x = 1; var y = 2;
+var total = add(x, y);
+print total;
+";
+
+    type Stop = (StopReason, u32, String);
+
+    // Records where each stop was and resumes as scripted, then continues
+    struct Scripted {
+        resumes: VecDeque<Resume>,
+        stops: Rc<RefCell<Vec<Stop>>>,
+    }
+
+    impl Frontend for Scripted {
+        fn stopped(
+            &mut self,
+            _session: &mut Session,
+            interpreter: &Interpreter,
+            reason: StopReason,
+            line: u32,
+        ) -> Resume {
+            let function = interpreter.call_stack()[0].function.clone();
+            self.stops.borrow_mut().push((reason, line, function));
+            self.resumes.pop_front().unwrap_or(Resume::Continue)
+        }
+    }
+
+    fn debug(session: Session, stop_on_entry: bool, resumes: &[Resume]) -> Vec<Stop> {
+        let stops = Rc::new(RefCell::new(Vec::new()));
+        let frontend = Scripted {
+            resumes: resumes.iter().copied().collect(),
+            stops: stops.clone(),
+        };
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output(Box::new(io::sink()));
+        interpreter.set_debugger(Box::new(Debugger::new(frontend, session, stop_on_entry)));
+        interpreter.interpret(Rox::compile(SCRIPT).unwrap()).unwrap();
+        stops.take()
+    }
+
+    fn breakpoints(lines: &[u32]) -> Session {
+        Session {
+            breakpoints: lines.iter().copied().collect(),
+            ..Session::default()
+        }
+    }
+
+    fn stop(reason: StopReason, line: u32, function: &str) -> Stop {
+        (reason, line, String::from(function))
+    }
+
+    #[test]
+    fn stops_on_entry_and_when_paused() {
+        let stops = debug(Session::default(), true, &[]);
+        assert_eq!(stops, [stop(StopReason::Entry, 1, "script")]);
+        let session = Session::default();
+        session.pause.store(true, Ordering::SeqCst);
+        assert_eq!(debug(session, false, &[]), [stop(StopReason::Pause, 1, "script")]);
+    }
+
+    #[test]
+    fn hits_a_breakpoint_once_per_line() {
+        let stops = debug(breakpoints(&[2, 5]), false, &[]);
+        assert_eq!(
+            stops,
+            [
+                stop(StopReason::Breakpoint, 5, "script"),
+                stop(StopReason::Breakpoint, 2, "add()"),
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_in_over_and_out() {
+        let resumes = [Resume::StepIn, Resume::StepOver, Resume::StepOut];
+        let stops = debug(breakpoints(&[6]), false, &resumes);
+        assert_eq!(
+            stops,
+            [
+                stop(StopReason::Breakpoint, 6, "script"),
+                stop(StopReason::Step, 2, "add()"),
+                stop(StopReason::Step, 3, "add()"),
+                stop(StopReason::Step, 7, "script"),
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let stops = debug(breakpoints(&[6]), false, &[Resume::StepOver]);
+        assert_eq!(
+            stops,
+            [
+                stop(StopReason::Breakpoint, 6, "script"),
+                stop(StopReason::Step, 7, "script"),
+            ]
+        );
+    }
+}
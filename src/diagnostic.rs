@@ -0,0 +1,87 @@
+use std::fmt;
+
+use crate::token::{Span, Token};
+use crate::token_type::TokenType;
+
+// An error tied to a place in the source, rendered rustc-style with the
+// offending line and a caret underline
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    // Short context such as " at 'x'", placed after "Error" in the header
+    pub location: String,
+    pub label: String,
+    pub span: Span,
+    source_line: String,
+    // Characters to underline, clipped to the first line of the span
+    underline: usize,
+}
+
+impl Diagnostic {
+    pub fn new(source: &str, span: Span, location: &str, message: &str, label: &str) -> Diagnostic {
+        Diagnostic {
+            message: String::from(message),
+            location: String::from(location),
+            label: String::from(label),
+            span,
+            source_line: line_containing(source, span.start),
+            underline: underline_width(source, span),
+        }
+    }
+
+    pub fn at_token(source: &str, token: &Token, message: &str, label: &str) -> Diagnostic {
+        let location = match token.token_type {
+            TokenType::EOF => String::from(" at end"),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+        Diagnostic::new(source, token.span, &location, message, label)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "[line {}] Error{}: {}",
+            self.span.line, self.location, self.message
+        )?;
+
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so the caret lines up under the source text
+        let indent = self
+            .source_line
+            .chars()
+            .take(self.span.column.saturating_sub(1) as usize)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", number, self.source_line)?;
+        write!(
+            f,
+            "{} | {}{} {}",
+            gutter,
+            indent,
+            "^".repeat(self.underline),
+            self.label
+        )
+    }
+}
+
+fn underline_width(source: &str, span: Span) -> usize {
+    let end = span.end.min(source.len());
+    let start = span.start.min(end);
+    source[start..end]
+        .lines()
+        .next()
+        .map_or(0, |line| line.chars().count())
+        .max(1)
+}
+
+fn line_containing(source: &str, offset: usize) -> String {
+    let offset = offset.min(source.len());
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+    source[start..end].trim_end_matches('\r').to_string()
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::io;
+
+use crate::diagnostic::Diagnostic;
+use crate::interpreter::InterpreterError;
+
+// Everything that can go wrong running a script through the library
+#[derive(Debug)]
+pub enum RoxError {
+    Io(io::Error),
+    // Scanner and parser errors, in source order
+    Parse(Vec<Diagnostic>),
+    Resolve(Vec<Diagnostic>),
+    Runtime(InterpreterError),
+    // A compiled script that is damaged or from another version
+    Load(String),
+}
+
+impl fmt::Display for RoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoxError::Io(e) => write!(f, "Could not read script: {}", e),
+            RoxError::Parse(diagnostics) | RoxError::Resolve(diagnostics) => {
+                let reports = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.to_string())
+                    .collect::<Vec<String>>();
+                write!(f, "{}", reports.join("\n"))
+            }
+            RoxError::Runtime(e) => write!(f, "{}", e),
+            RoxError::Load(message) => write!(f, "Could not load compiled script: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RoxError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RoxError {
+    fn from(e: io::Error) -> RoxError {
+        RoxError::Io(e)
+    }
+}
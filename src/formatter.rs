@@ -0,0 +1,578 @@
+use crate::error::RoxError;
+use crate::scanner::Scanner;
+use crate::token::{Token, Trivia, TriviaKind};
+use crate::token_type::TokenType;
+use crate::Rox;
+
+const INDENT: &str = "    ";
+pub const MAX_WIDTH: usize = 80;
+
+// Formats a whole program. Code that doesn't parse is refused so a
+// formatting run can never change what a program means.
+//
+// Layout comes from the token stream alone: line breaks inside a statement
+// are thrown away and chosen again, which keeps the output stable when it
+// is formatted a second time. Comments and single blank lines between
+// statements are kept.
+pub fn format_source(source: &str) -> Result<String, RoxError> {
+    Rox::parse(source)?;
+    let tokens = Scanner::with_trivia(source).scan_tokens();
+    let mut formatter = Formatter::new(&tokens);
+    formatter.run();
+    Ok(formatter.render())
+}
+
+struct Piece {
+    text: String,
+    space_before: bool,
+    // Parentheses open around the piece
+    depth: usize,
+    token_type: Option<TokenType>,
+    // Binding strength when the piece is a binary operator
+    precedence: Option<u8>,
+}
+
+// A line of output before wrapping. No pieces means a blank line.
+struct Line {
+    indent: usize,
+    pieces: Vec<Piece>,
+}
+
+struct Formatter<'a> {
+    tokens: &'a [Token],
+    // Whether each ')' closes an if, while or for header rather than an
+    // expression
+    closes_header: Vec<bool>,
+    // For a ')' closing a header whose body is an if with an else, the last
+    // token of that body. The body goes on its own indented line so the else
+    // lines up with the if it belongs to.
+    nested_if_ends: Vec<Option<usize>>,
+    // Last tokens of the indented bodies still open
+    body_ends: Vec<usize>,
+    lines: Vec<Line>,
+    current: Vec<Piece>,
+    indent: usize,
+    depth: usize,
+    // The statement being written has already been broken across lines
+    continued: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(tokens: &'a [Token]) -> Formatter<'a> {
+        let mut closes_header = vec![false; tokens.len()];
+        let mut open = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token.token_type {
+                TokenType::LeftParen => open.push(i),
+                TokenType::RightParen => {
+                    if let Some(start) = open.pop() {
+                        closes_header[i] = start > 0
+                            && matches!(
+                                tokens[start - 1].token_type,
+                                TokenType::If | TokenType::While | TokenType::For
+                            );
+                    }
+                }
+                _ => (),
+            }
+        }
+        let mut nested_if_ends = vec![None; tokens.len()];
+        let mut i = 0;
+        while i < tokens.len() && tokens[i].token_type != TokenType::EOF {
+            i = statement_end(tokens, i, &mut nested_if_ends).0 + 1;
+        }
+        Formatter {
+            tokens,
+            closes_header,
+            nested_if_ends,
+            body_ends: Vec::new(),
+            lines: Vec::new(),
+            current: Vec::new(),
+            indent: 0,
+            depth: 0,
+            continued: false,
+        }
+    }
+
+    fn run(&mut self) {
+        let mut i = 0;
+        while i < self.tokens.len() {
+            let token = &self.tokens[i];
+            self.leading_trivia(token);
+            match token.token_type {
+                TokenType::EOF => break,
+                TokenType::LeftBrace => {
+                    self.push(i);
+                    let next = &self.tokens[i + 1];
+                    let empty = next.token_type == TokenType::RightBrace
+                        && !has_comment(token.trailing_trivia())
+                        && !has_comment(next.leading_trivia());
+                    if empty {
+                        i += 1;
+                        self.push(i);
+                        self.after_right_brace(i);
+                    } else {
+                        self.trailing_comments(token);
+                        self.end_line();
+                        self.indent += 1;
+                    }
+                }
+                TokenType::RightBrace => {
+                    self.end_line();
+                    self.indent = self.indent.saturating_sub(1);
+                    self.push(i);
+                    self.after_right_brace(i);
+                }
+                TokenType::Semicolon if self.depth == 0 => {
+                    self.push(i);
+                    self.trailing_comments(token);
+                    self.end_line();
+                }
+                TokenType::RightParen if self.nested_if_ends[i].is_some() => {
+                    self.push(i);
+                    self.trailing_comments(token);
+                    self.end_line();
+                    self.indent += 1;
+                    self.body_ends.extend(self.nested_if_ends[i]);
+                }
+                _ => {
+                    self.push(i);
+                    if self.trailing_comments(token) {
+                        self.break_line();
+                    }
+                }
+            }
+            while self.body_ends.last() == Some(&i) {
+                self.body_ends.pop();
+                self.indent -= 1;
+            }
+            i += 1;
+        }
+        self.end_line();
+    }
+
+    // Blank lines and comments before a token
+    fn leading_trivia(&mut self, token: &Token) {
+        let mut newlines = 0;
+        for trivia in token.leading_trivia() {
+            match trivia.kind {
+                TriviaKind::Newline => newlines += 1,
+                TriviaKind::Comment => {
+                    if !self.current.is_empty() {
+                        self.break_line();
+                    } else if newlines > 1 && !self.continued {
+                        self.blank_line();
+                    }
+                    self.current.push(comment(&trivia.text, false));
+                    self.flush();
+                    newlines = 0;
+                }
+                TriviaKind::Whitespace | TriviaKind::Unknown => (),
+            }
+        }
+        let keeps_blank = !matches!(token.token_type, TokenType::RightBrace | TokenType::EOF);
+        if newlines > 1 && keeps_blank && self.current.is_empty() && !self.continued {
+            self.blank_line();
+        }
+    }
+
+    // Comments after a token on its line. Returns whether there were any.
+    fn trailing_comments(&mut self, token: &Token) -> bool {
+        let mut any = false;
+        for trivia in token.trailing_trivia() {
+            if trivia.kind == TriviaKind::Comment {
+                self.current.push(comment(&trivia.text, true));
+                any = true;
+            }
+        }
+        any
+    }
+
+    fn after_right_brace(&mut self, i: usize) {
+        let commented = self.trailing_comments(&self.tokens[i]);
+        let ends_body = self.body_ends.last() == Some(&i);
+        if commented || ends_body || self.tokens[i + 1].token_type != TokenType::Else {
+            self.end_line();
+        }
+    }
+
+    fn push(&mut self, i: usize) {
+        let token_type = self.tokens[i].token_type.clone();
+        let space_before = !self.current.is_empty() && self.space_before(i);
+        if token_type == TokenType::RightParen {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        self.current.push(Piece {
+            text: self.tokens[i].lexeme.to_string(),
+            space_before,
+            depth: self.depth,
+            precedence: self.precedence(i),
+            token_type: Some(token_type.clone()),
+        });
+        if token_type == TokenType::LeftParen {
+            self.depth += 1;
+        }
+    }
+
+    fn space_before(&self, i: usize) -> bool {
+        use TokenType::*;
+        let prev = &self.tokens[i - 1].token_type;
+        let next = &self.tokens[i].token_type;
+        match (prev, next) {
+            (_, RightParen | Comma | Semicolon | Dot) => false,
+            (LeftParen | Dot, _) => false,
+            (LeftBrace, RightBrace) => false,
+            // Keeps "- -a" from becoming "--a"
+            (Minus, Minus | MinusMinus) => true,
+            (_, PlusPlus | MinusMinus) if self.ends_operand(i - 1) => false,
+            _ if self.is_prefix(i - 1) => false,
+            (_, LeftParen) => !self.ends_operand(i - 1),
+            _ => true,
+        }
+    }
+
+    // Whether the token can end an operand, so a following '(' is a call
+    // and a following '-' is binary
+    fn ends_operand(&self, i: usize) -> bool {
+        use TokenType::*;
+        match self.tokens[i].token_type {
+            Identifier | Number | String | True | False | Nil | This => true,
+            RightParen => !self.closes_header[i],
+            PlusPlus | MinusMinus => i > 0 && self.ends_operand(i - 1),
+            _ => false,
+        }
+    }
+
+    fn is_prefix(&self, i: usize) -> bool {
+        use TokenType::*;
+        matches!(
+            self.tokens[i].token_type,
+            Minus | Bang | PlusPlus | MinusMinus
+        ) && (i == 0 || !self.ends_operand(i - 1))
+    }
+
+    fn precedence(&self, i: usize) -> Option<u8> {
+        use TokenType::*;
+        if self.is_prefix(i) {
+            return None;
+        }
+        match self.tokens[i].token_type {
+            Or => Some(1),
+            And => Some(2),
+            EqualEqual | BangEqual => Some(3),
+            // Not the '<' in "class A < B"
+            Less if i > 1 && self.tokens[i - 2].token_type == Class => None,
+            Less | LessEqual | Greater | GreaterEqual => Some(4),
+            Plus | Minus => Some(5),
+            Star | Slash => Some(6),
+            _ => None,
+        }
+    }
+
+    // Writes out the current line
+    fn flush(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let pieces = std::mem::take(&mut self.current);
+        self.lines.push(Line {
+            indent: self.indent + self.continued as usize,
+            pieces,
+        });
+    }
+
+    // Ends a statement
+    fn end_line(&mut self) {
+        self.flush();
+        self.continued = false;
+    }
+
+    // Breaks a statement that carries on on the next line
+    fn break_line(&mut self) {
+        self.flush();
+        self.continued = true;
+    }
+
+    fn blank_line(&mut self) {
+        let after_open_brace = match self.lines.last() {
+            None => true,
+            Some(line) => {
+                let last = line.pieces.iter().rev().find(|p| p.token_type.is_some());
+                line.pieces.is_empty()
+                    || last.map(|p| &p.text) == Some(&String::from("{"))
+            }
+        };
+        if !after_open_brace {
+            self.lines.push(Line {
+                indent: 0,
+                pieces: Vec::new(),
+            });
+        }
+    }
+
+    fn render(&mut self) -> String {
+        let mut wrapped = Vec::new();
+        for line in std::mem::take(&mut self.lines) {
+            wrap(line, &mut wrapped);
+        }
+        let mut out = String::new();
+        for line in wrapped {
+            if !line.pieces.is_empty() {
+                out.push_str(&INDENT.repeat(line.indent));
+            }
+            for (i, piece) in line.pieces.iter().enumerate() {
+                if i > 0 && piece.space_before {
+                    out.push(' ');
+                }
+                out.push_str(&piece.text);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+// Index of the last token of the statement starting at i, and whether it
+// is an if with an else, filling in nested_if_ends on the way. The tokens
+// are known to parse.
+fn statement_end(tokens: &[Token], i: usize, nested_if_ends: &mut [Option<usize>]) -> (usize, bool) {
+    use TokenType::*;
+    let last = tokens.len() - 1;
+    match tokens[i].token_type {
+        LeftBrace => {
+            let mut j = i + 1;
+            while j < last && tokens[j].token_type != RightBrace {
+                j = statement_end(tokens, j, nested_if_ends).0 + 1;
+            }
+            (j.min(last), false)
+        }
+        If | While | For => {
+            let close = closing_paren(tokens, i + 1);
+            if close >= last {
+                return (last, false);
+            }
+            let (end, nested_else) = statement_end(tokens, close + 1, nested_if_ends);
+            if nested_else {
+                nested_if_ends[close] = Some(end);
+            }
+            if tokens[i].token_type == If && end < last && tokens[end + 1].token_type == Else {
+                (statement_end(tokens, end + 2, nested_if_ends).0, true)
+            } else {
+                (end, false)
+            }
+        }
+        // Declarations and simple statements end at a ';' or with a body
+        _ => {
+            let mut depth = 0usize;
+            for j in i..last {
+                match tokens[j].token_type {
+                    LeftParen => depth += 1,
+                    RightParen => depth = depth.saturating_sub(1),
+                    Semicolon if depth == 0 => return (j, false),
+                    LeftBrace if depth == 0 => return statement_end(tokens, j, nested_if_ends),
+                    _ => (),
+                }
+            }
+            (last, false)
+        }
+    }
+}
+
+// Index of the ')' matching the '(' at i
+fn closing_paren(tokens: &[Token], i: usize) -> usize {
+    let mut depth = 0;
+    for (j, token) in tokens.iter().enumerate().skip(i) {
+        match token.token_type {
+            TokenType::LeftParen => depth += 1,
+            TokenType::RightParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return j;
+                }
+            }
+            _ => (),
+        }
+    }
+    tokens.len() - 1
+}
+
+fn has_comment(trivia: &[Trivia]) -> bool {
+    trivia.iter().any(|trivia| trivia.kind == TriviaKind::Comment)
+}
+
+fn comment(text: &str, space_before: bool) -> Piece {
+    Piece {
+        text: String::from(text.trim_end()),
+        space_before,
+        depth: 0,
+        token_type: None,
+        precedence: None,
+    }
+}
+
+fn width(line: &Line) -> usize {
+    let text: usize = line
+        .pieces
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| piece.text.chars().count() + (i > 0 && piece.space_before) as usize)
+        .sum();
+    line.indent * INDENT.len() + text
+}
+
+// Splits lines longer than MAX_WIDTH, first by putting the arguments of the
+// outermost call or parameter list on their own lines, then before the
+// loosest binary operators
+fn wrap(line: Line, out: &mut Vec<Line>) {
+    if width(&line) <= MAX_WIDTH {
+        out.push(line);
+        return;
+    }
+    let indent = line.indent;
+    let mut pieces = line.pieces;
+    let split = split_at_commas(&pieces).or_else(|| split_at_operators(&pieces));
+    let Some(split) = split else {
+        out.push(Line { indent, pieces });
+        return;
+    };
+    // Split points are in increasing order; peel segments off the end
+    let mut segments = Vec::new();
+    for (at, extra_indent) in split.into_iter().rev() {
+        let mut segment = pieces.split_off(at);
+        segment[0].space_before = false;
+        segments.push(Line {
+            indent: indent + extra_indent,
+            pieces: segment,
+        });
+    }
+    segments.push(Line { indent, pieces });
+    for segment in segments.into_iter().rev() {
+        wrap(segment, out);
+    }
+}
+
+// Start index and extra indent of each new line
+type Split = Vec<(usize, usize)>;
+
+fn split_at_commas(pieces: &[Piece]) -> Option<Split> {
+    let is = |piece: &Piece, token_type: TokenType| piece.token_type == Some(token_type);
+    let depth = pieces
+        .iter()
+        .filter(|p| is(p, TokenType::Comma))
+        .map(|p| p.depth)
+        .min()?;
+    let comma = pieces
+        .iter()
+        .position(|p| is(p, TokenType::Comma) && p.depth == depth)?;
+    let open = pieces[..comma]
+        .iter()
+        .rposition(|p| is(p, TokenType::LeftParen) && p.depth == depth - 1)?;
+    let close = comma
+        + pieces[comma..]
+            .iter()
+            .position(|p| is(p, TokenType::RightParen) && p.depth == depth - 1)?;
+
+    let mut split = vec![(open + 1, 1)];
+    for (i, piece) in pieces.iter().enumerate().take(close).skip(open + 1) {
+        if is(piece, TokenType::Comma) && piece.depth == depth {
+            split.push((i + 1, 1));
+        }
+    }
+    split.push((close, 0));
+    Some(split)
+}
+
+fn split_at_operators(pieces: &[Piece]) -> Option<Split> {
+    let depth = pieces
+        .iter()
+        .filter(|p| p.precedence.is_some())
+        .map(|p| p.depth)
+        .min()?;
+    let loosest = pieces
+        .iter()
+        .filter(|p| p.depth == depth)
+        .filter_map(|p| p.precedence)
+        .min()?;
+    let split = pieces
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, p)| p.depth == depth && p.precedence == Some(loosest))
+        .map(|(i, _)| (i, 1))
+        .collect::<Split>();
+    if split.is_empty() {
+        None
+    } else {
+        Some(split)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_printer::print_program;
+
+    fn fmt(source: &str) -> String {
+        format_source(source).expect("formats")
+    }
+
+    #[test]
+    fn lines_up_else_with_its_if() {
+        assert_eq!(
+            fmt("if (a) if (b) print 1; else print 2;"),
+            "if (a)\n    if (b) print 1;\n    else print 2;\n"
+        );
+        assert_eq!(
+            fmt("if (a) if (b) print 1; else print 2; else print 3;"),
+            "if (a)\n    if (b) print 1;\n    else print 2;\nelse print 3;\n"
+        );
+        assert_eq!(
+            fmt("while (a) if (b) print 1; else { print 2; } print 3;"),
+            "while (a)\n    if (b) print 1;\n    else {\n        print 2;\n    }\nprint 3;\n"
+        );
+        assert_eq!(
+            fmt("if (a) if (b) print 1; else {print 2;} else print 3;"),
+            "if (a)\n    if (b) print 1;\n    else {\n        print 2;\n    }\nelse print 3;\n"
+        );
+    }
+
+    #[test]
+    fn leaves_plain_if_else_alone() {
+        assert_eq!(fmt("if (a) print 1; else print 2;"), "if (a) print 1;\nelse print 2;\n");
+        assert_eq!(
+            fmt("if (a) print 1; else if (b) print 2; else print 3;"),
+            "if (a) print 1;\nelse if (b) print 2;\nelse print 3;\n"
+        );
+        assert_eq!(fmt("if (a) if (b) print 1;"), "if (a) if (b) print 1;\n");
+    }
+
+    #[test]
+    fn is_idempotent() {
+        let sources = [
+            "if (a) if (b) if (c) x; else y; else z;",
+            "for (var i = 0; i < 10; i = i + 1) if (i > 5) print i; else print -i;",
+            "fun f(a, b) { if (a) if (b) return 1; else return 2; return 3; }",
+            "class A < B { init() { this.x = 1; } m() { while (a) if (b) x; else y; } }",
+            "if (a) // outer\n  // lead\n  if (b) x; // then\n  else y; // else\nprint 1;\n",
+            "// header\n\n\nvar a = 1;\n\n\n\nprint a;   // trailing\n{\n\n}\n",
+            "print aVeryLongFunctionName(firstArgument, secondArgument, thirdArgument, fourth);",
+            "var total = alpha + beta * gamma - delta / epsilon + zeta * eta - theta + iota + kappa;",
+            "a.b.c = - -x; a++; --b; print !(a and b or c);",
+        ];
+        for source in sources {
+            let once = fmt(source);
+            assert_eq!(fmt(&once), once, "formatting twice changed:\n{}", once);
+            assert_eq!(
+                print_program(&Rox::parse(&once).unwrap()),
+                print_program(&Rox::parse(source).unwrap()),
+                "formatting changed the program:\n{}",
+                once
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_code_that_does_not_parse() {
+        assert!(format_source("if (a print 1;").is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::callable::{Callable, RoxFunction};
+use crate::class::{RoxClass, RoxInstance};
+use crate::interpreter::Environment;
+use crate::rox_type::RoxType;
+
+const DEFAULT_THRESHOLD: usize = 10_000;
+
+// Reference counting frees values as soon as nothing refers to them, except
+// for cycles, such as an instance holding a closure over itself. Classes,
+// functions and enclosing scopes only refer to things made before them, so
+// every cycle runs through an instance or through a scope some function
+// closes over. The heap keeps weak handles to those and periodically looks
+// for the ones only kept alive by each other.
+//
+// It works like CPython's collector: counting the references the objects
+// hold to each other and taking those from their reference counts leaves
+// the references from outside, from the interpreter's globals and scopes,
+// the Rust stack or an embedding program. Objects with any are alive, along
+// with everything they reach. Emptying the rest breaks their cycles and
+// reference counting frees them. Anything the tracer misses only keeps
+// objects alive, never frees a live one.
+pub(crate) struct Heap {
+    environments: Vec<Weak<RefCell<Environment>>>,
+    instances: Vec<Weak<RefCell<RoxInstance>>>,
+    threshold: usize,
+    // Tracked objects, live or not, at which to collect next
+    next_collection: usize,
+    stress: bool,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Heap {
+        Heap {
+            environments: Vec::new(),
+            instances: Vec::new(),
+            threshold: DEFAULT_THRESHOLD,
+            next_collection: DEFAULT_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    // Collects after at least this many allocations, and after as many as
+    // are live when that's more, so collecting stays in proportion to the
+    // work done
+    pub(crate) fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold.max(1);
+        self.next_collection = self.tracked() + self.threshold;
+    }
+
+    pub(crate) fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    // Called for scopes functions close over, once each
+    pub(crate) fn track_environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        if environment.borrow_mut().mark_tracked() {
+            self.environments.push(Rc::downgrade(environment));
+            self.allocated();
+        }
+    }
+
+    pub(crate) fn track_instance(&mut self, instance: &Rc<RefCell<RoxInstance>>) {
+        self.instances.push(Rc::downgrade(instance));
+        self.allocated();
+    }
+
+    fn tracked(&self) -> usize {
+        self.environments.len() + self.instances.len()
+    }
+
+    fn allocated(&mut self) {
+        if self.stress || self.tracked() >= self.next_collection {
+            self.collect();
+        }
+    }
+
+    // Frees unreachable cycles and returns how many objects were emptied
+    pub(crate) fn collect(&mut self) -> usize {
+        self.environments.retain(|environment| environment.strong_count() > 0);
+        self.instances.retain(|instance| instance.strong_count() > 0);
+
+        let mut graph = Graph::default();
+        for environment in self.environments.iter().filter_map(Weak::upgrade) {
+            graph.add(Node::Environment(environment));
+        }
+        for instance in self.instances.iter().filter_map(Weak::upgrade) {
+            graph.add(Node::Instance(instance));
+        }
+
+        // Everything the tracked objects reach, and what refers to what.
+        // Node i's children are children[ranges[i]]; None for objects
+        // borrowed right now, which can't be looked into.
+        let mut children = Vec::new();
+        let mut ranges = Vec::new();
+        let mut tracer = Tracer { edges: Vec::new() };
+        let mut next = 0;
+        while next < graph.nodes.len() {
+            let traced = graph.nodes[next].trace(&mut tracer);
+            let start = children.len();
+            for child in tracer.edges.drain(..) {
+                children.push(graph.add(child));
+            }
+            ranges.push(traced.then_some(start..children.len()));
+            next += 1;
+        }
+
+        let mut internal = vec![0; graph.nodes.len()];
+        for &child in &children {
+            internal[child] += 1;
+        }
+        // The graph holds one reference to each object itself
+        let mut pending = (0..graph.nodes.len())
+            .filter(|&i| ranges[i].is_none() || graph.nodes[i].strong_count() - 1 > internal[i])
+            .collect::<Vec<usize>>();
+        let mut alive = vec![false; graph.nodes.len()];
+        while let Some(i) = pending.pop() {
+            if alive[i] {
+                continue;
+            }
+            alive[i] = true;
+            if let Some(range) = ranges[i].clone() {
+                pending.extend(children[range].iter().filter(|&&child| !alive[child]));
+            }
+        }
+
+        let mut freed = 0;
+        for (node, _) in graph.nodes.iter().zip(alive).filter(|(_, alive)| !alive) {
+            if node.clear() {
+                freed += 1;
+            }
+        }
+        // Dropping the graph lets go of the last references to the garbage
+        drop(graph);
+
+        self.environments.retain(|environment| environment.strong_count() > 0);
+        self.instances.retain(|instance| instance.strong_count() > 0);
+        let live = self.tracked();
+        self.next_collection = live + self.threshold.max(live);
+        freed
+    }
+}
+
+// Collects the references an object holds to other heap objects
+pub struct Tracer {
+    edges: Vec<Node>,
+}
+
+impl Tracer {
+    pub fn value(&mut self, value: &RoxType) {
+        match value {
+            RoxType::Callable(callable) => self.edges.push(Node::Callable(callable.clone())),
+            RoxType::Class(class) => self.class(class),
+            RoxType::Instance(instance) => self.edges.push(Node::Instance(instance.clone())),
+            RoxType::String(_) | RoxType::Number(_) | RoxType::Nil | RoxType::Boolean(_) => (),
+        }
+    }
+
+    pub(crate) fn environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        self.edges.push(Node::Environment(environment.clone()));
+    }
+
+    pub(crate) fn function(&mut self, function: &Rc<RoxFunction>) {
+        self.edges.push(Node::Function(function.clone()));
+    }
+
+    pub(crate) fn class(&mut self, class: &Rc<RoxClass>) {
+        self.edges.push(Node::Class(class.clone()));
+    }
+}
+
+enum Node {
+    Environment(Rc<RefCell<Environment>>),
+    Instance(Rc<RefCell<RoxInstance>>),
+    Class(Rc<RoxClass>),
+    Function(Rc<RoxFunction>),
+    Callable(Rc<dyn Callable>),
+}
+
+impl Node {
+    // The same object can be reached as a function and as a callable
+    fn address(&self) -> usize {
+        match self {
+            Node::Environment(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Instance(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Class(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Function(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Callable(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(rc) => Rc::strong_count(rc),
+            Node::Instance(rc) => Rc::strong_count(rc),
+            Node::Class(rc) => Rc::strong_count(rc),
+            Node::Function(rc) => Rc::strong_count(rc),
+            Node::Callable(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // False if the object is borrowed and can't be looked into
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match self {
+            Node::Environment(environment) => match environment.try_borrow() {
+                Ok(environment) => environment.trace(tracer),
+                Err(_) => return false,
+            },
+            Node::Instance(instance) => match instance.try_borrow() {
+                Ok(instance) => instance.trace(tracer),
+                Err(_) => return false,
+            },
+            Node::Class(class) => class.trace(tracer),
+            Node::Function(function) => function.trace(tracer),
+            Node::Callable(callable) => callable.trace(tracer),
+        }
+        true
+    }
+
+    // Drops what a garbage object refers to. Only environments and
+    // instances can be emptied; the rest go when they do.
+    fn clear(&self) -> bool {
+        match self {
+            Node::Environment(environment) => match environment.try_borrow_mut() {
+                Ok(mut environment) => environment.clear(),
+                Err(_) => return false,
+            },
+            Node::Instance(instance) => match instance.try_borrow_mut() {
+                Ok(mut instance) => instance.clear(),
+                Err(_) => return false,
+            },
+            Node::Class(_) | Node::Function(_) | Node::Callable(_) => return false,
+        }
+        true
+    }
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    index: HashMap<usize, usize>,
+}
+
+impl Graph {
+    // Returns the node's index, adding it if it's new
+    fn add(&mut self, node: Node) -> usize {
+        let next = self.nodes.len();
+        let index = *self.index.entry(node.address()).or_insert(next);
+        if index == next {
+            self.nodes.push(node);
+        }
+        index
+    }
+}
@@ -1,196 +1,928 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
 
 use crate::{
+    callable::{Callable, NativeFunction, RoxFunction},
+    class::{get_property, RoxClass},
+    debugger::DebugHook,
+    diagnostic::Diagnostic,
+    gc::{Heap, Tracer},
+    natives,
     parser::{Expr, Stmt},
     rox_type::RoxType,
+    symbol::{Symbol, SymbolMap},
     token::Token,
     token_type::TokenType,
 };
 
-#[derive(Debug)]
+// Each call recurses on the Rust stack, so deeper ones are stopped with an
+// error before they can overflow it. A release build needs about 3KB of
+// stack per call, a debug build about 20KB. The VM stops at the same depth.
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
 pub struct Interpreter {
     pub had_runtime_error: bool,
-    environment: Environment,
+    pub(crate) globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    pub(crate) heap: Heap,
+    // Where print writes
+    output: Box<dyn Write>,
+    debugger: Option<Box<dyn DebugHook>>,
+    // Calls in progress, outermost first. Only kept while a debugger is
+    // attached.
+    frames: Vec<Frame>,
+    // Lox calls in progress
+    call_depth: usize,
+}
+
+struct Frame {
+    function: String,
+    line: u32,
+    // The frame's scope when it made the call above it; the innermost
+    // frame's scope is the interpreter's current environment
+    environment: Rc<RefCell<Environment>>,
+}
+
+impl fmt::Debug for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("had_runtime_error", &self.had_runtime_error)
+            .field("globals", &self.globals)
+            .field("debugging", &self.debugger.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug)]
 pub struct InterpreterError {
     pub error_string: String,
+    // The token being evaluated when the error happened; natives leave this
+    // empty and the call site fills it in
+    pub token: Option<Box<Token>>,
+    // Innermost call first, ending with the top-level script
+    pub stack: Vec<StackFrame>,
+    // Line reached in the frame the error is currently unwinding through
+    line: u32,
+    // The error against its source line, once the source is known
+    pub diagnostic: Option<Box<Diagnostic>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    pub function: String,
+    pub line: u32,
+}
+
+impl InterpreterError {
+    // An error raised outside the interpreter, such as by a native function
+    pub fn new(message: &str) -> InterpreterError {
+        InterpreterError {
+            error_string: String::from(message),
+            token: None,
+            stack: Vec::new(),
+            line: 0,
+            diagnostic: None,
+        }
+    }
+
+    pub fn at(token: &Token, message: &str) -> InterpreterError {
+        InterpreterError {
+            error_string: String::from(message),
+            token: Some(Box::new(token.clone())),
+            stack: Vec::new(),
+            line: token.span.line,
+            diagnostic: None,
+        }
+    }
+
+    // Points the error at its token in the source it was raised from
+    pub fn locate(mut self, source: &str) -> InterpreterError {
+        if let Some(token) = &self.token {
+            let diagnostic = Diagnostic::at_token(source, token, &self.error_string, "raised here");
+            self.diagnostic = Some(Box::new(diagnostic));
+        }
+        self
+    }
+
+    // Records the frame of a function the error unwound out of, which was
+    // called from call_site
+    fn unwind_through(&mut self, function: &str, call_site: &Token) {
+        if self.token.is_none() {
+            // Natives have no frame of their own; blame the call
+            self.token = Some(Box::new(call_site.clone()));
+        } else {
+            self.stack.push(StackFrame {
+                function: format!("{}()", function),
+                line: self.line,
+            });
+        }
+        self.line = call_site.span.line;
+    }
+}
+
+// Prints the diagnostic, or without the source just its header, followed by
+// a traceback, innermost call first
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.diagnostic, &self.token) {
+            (Some(diagnostic), _) => write!(f, "{}", diagnostic)?,
+            (None, Some(token)) => write!(
+                f,
+                "[line {}] Error at '{}': {}",
+                token.span.line, token.lexeme, self.error_string
+            )?,
+            (None, None) => write!(f, "{}", self.error_string)?,
+        }
+        for line in traceback(&self.stack) {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs of frames longer than this, counted after collapsing repeats, only
+// show this many at each end
+const TRACEBACK_ENDS: usize = 10;
+
+// The traceback's lines. A frame repeated by recursion is shown once with a
+// count, so a stack overflow doesn't print thousands of lines.
+fn traceback(stack: &[StackFrame]) -> Vec<String> {
+    let mut runs: Vec<(&StackFrame, usize)> = Vec::new();
+    for frame in stack {
+        match runs.last_mut() {
+            Some((last, count)) if last.function == frame.function && last.line == frame.line => {
+                *count += 1
+            }
+            _ => runs.push((frame, 1)),
+        }
+    }
+    let mut hidden = 0;
+    if runs.len() > 2 * TRACEBACK_ENDS {
+        let middle = runs.drain(TRACEBACK_ENDS..runs.len() - TRACEBACK_ENDS);
+        hidden = middle.map(|(_, count)| count).sum();
+    }
+
+    let mut lines = Vec::new();
+    for (i, (frame, count)) in runs.into_iter().enumerate() {
+        if i == TRACEBACK_ENDS && hidden > 0 {
+            lines.push(format!("... {} more frames", hidden));
+        }
+        lines.push(format!("[line {}] in {}", frame.line, frame.function));
+        if count > 1 {
+            lines.push(format!("... {} more frames in {}", count - 1, frame.function));
+        }
+    }
+    lines
 }
 
 pub type InterpreterResult = Result<RoxType, InterpreterError>;
 
+// Ways a statement can stop executing before it completes
 #[derive(Debug)]
-struct Environment {
-    values: HashMap<String, RoxType>,
+pub enum Unwind {
+    Return(RoxType),
+    Error(InterpreterError),
+}
+
+impl From<InterpreterError> for Unwind {
+    fn from(e: InterpreterError) -> Unwind {
+        Unwind::Error(e)
+    }
+}
+
+// Variable bindings for one scope, chained to the scope that encloses it
+#[derive(Debug)]
+pub(crate) struct Environment {
+    values: SymbolMap<RoxType>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+    // Whether the cycle collector has been told about this scope
+    tracked: bool,
 }
 
 impl Environment {
     fn new() -> Environment {
         Environment {
-            values: HashMap::new(),
+            values: SymbolMap::default(),
+            enclosing: None,
+            tracked: false,
         }
     }
 
-    fn get(&self, token: Token) -> Result<RoxType, InterpreterError> {
-        match self.values.get(&token.lexeme) {
-            Some(val) => return Ok(val.clone()),
-            None => {
-                return Err(InterpreterError {
-                    error_string: String::from("Undefined varaible"),
-                });
-            }
+    pub(crate) fn with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Environment {
+        Environment {
+            values: SymbolMap::default(),
+            enclosing: Some(enclosing),
+            tracked: false,
+        }
+    }
+
+    fn get(&self, token: &Token) -> Result<RoxType, InterpreterError> {
+        if let Some(val) = self.values.get(&token.lexeme.symbol()) {
+            return Ok(val.clone());
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow().get(token),
+            None => Err(undefined_variable(token)),
+        }
+    }
+
+    pub(crate) fn define(&mut self, name: Symbol, roxt: RoxType) {
+        self.values.insert(name, roxt);
+    }
+
+    // Names declared in this scope, sorted
+    pub(crate) fn names(&self) -> Vec<String> {
+        let mut names = self.values.keys().map(|name| name.to_string()).collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values.values() {
+            tracer.value(value);
+        }
+        if let Some(enclosing) = &self.enclosing {
+            tracer.environment(enclosing);
         }
     }
 
-    fn define(&mut self, token: Token, roxt: RoxType) {
-        self.values.insert(token.lexeme, roxt);
+    // True the first time it's called
+    pub(crate) fn mark_tracked(&mut self) -> bool {
+        !std::mem::replace(&mut self.tracked, true)
+    }
+
+    // Empties a scope the collector found unreachable
+    pub(crate) fn clear(&mut self) {
+        self.values.clear();
+        self.enclosing = None;
+    }
+
+    // Looks only in this scope, without walking the chain
+    pub(crate) fn get_local(&self, name: Symbol) -> Option<RoxType> {
+        self.values.get(&name).cloned()
+    }
+
+    // Assigns to the nearest scope that declares the variable
+    fn assign(&mut self, token: &Token, roxt: RoxType) -> Result<(), InterpreterError> {
+        if let Some(val) = self.values.get_mut(&token.lexeme.symbol()) {
+            *val = roxt;
+            return Ok(());
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow_mut().assign(token, roxt),
+            None => Err(undefined_variable(token)),
+        }
+    }
+}
+
+impl Environment {
+    // Lookups at a depth resolved ahead of time, skipping the scope chain walk
+    fn get_at(&self, depth: usize, token: &Token) -> Result<RoxType, InterpreterError> {
+        if depth == 0 {
+            return self.get(token);
+        }
+        self.ancestor(depth).borrow().get(token)
+    }
+
+    fn assign_at(
+        &mut self,
+        depth: usize,
+        token: &Token,
+        roxt: RoxType,
+    ) -> Result<(), InterpreterError> {
+        if depth == 0 {
+            return self.assign(token, roxt);
+        }
+        self.ancestor(depth).borrow_mut().assign(token, roxt)
+    }
+
+    fn get_local_at(&self, depth: usize, name: Symbol) -> Option<RoxType> {
+        if depth == 0 {
+            return self.get_local(name);
+        }
+        self.ancestor(depth).borrow().get_local(name)
+    }
+
+    fn ancestor(&self, depth: usize) -> Rc<RefCell<Environment>> {
+        let mut environment = self
+            .enclosing
+            .clone()
+            .expect("resolved depth exceeds scope chain");
+        for _ in 1..depth {
+            let enclosing = environment
+                .borrow()
+                .enclosing
+                .clone()
+                .expect("resolved depth exceeds scope chain");
+            environment = enclosing;
+        }
+        environment
+    }
+}
+
+fn undefined_variable(token: &Token) -> InterpreterError {
+    InterpreterError::at(token, &format!("Undefined variable '{}'.", token.lexeme))
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
     }
 }
 
 impl Interpreter {
     pub fn new() -> Interpreter {
-        Interpreter {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        let mut interpreter = Interpreter {
             had_runtime_error: false,
-            environment: Environment::new(),
+            globals: globals.clone(),
+            environment: globals,
+            heap: Heap::new(),
+            output: Box::new(io::stdout()),
+            debugger: None,
+            frames: Vec::new(),
+            call_depth: 0,
+        };
+        natives::define_builtins(&mut interpreter);
+        interpreter
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<RoxType> {
+        self.globals.borrow().get_local(Symbol::intern(name))
+    }
+
+    // Every global, including the natives, in name order
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals.borrow().names()
+    }
+
+    // Defines or overwrites a global variable
+    pub fn set_global(&mut self, name: &str, value: RoxType) {
+        self.globals.borrow_mut().define(Symbol::intern(name), value)
+    }
+
+    // Registers a Rust function as a global callable; arity is checked before
+    // the function runs, so it can rely on the argument count
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[RoxType]) -> InterpreterResult + 'static,
+    {
+        self.define_native_function(NativeFunction::new(name, arity, Box::new(function)));
+    }
+
+    pub(crate) fn define_native_function(&mut self, native: NativeFunction) {
+        self.globals
+            .borrow_mut()
+            .define(Symbol::intern(&native.name()), RoxType::Callable(Rc::new(native)));
+    }
+
+    // Looks for unreachable cycles after this many scopes and instances have
+    // been allocated, or after as many as are live if that's more
+    pub fn set_gc_threshold(&mut self, threshold: usize) {
+        self.heap.set_threshold(threshold);
+    }
+
+    // Collects on every allocation, to flush out values freed too early
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    // Frees unreachable cycles now and returns how many objects were emptied
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    // Sends the output of print statements somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Attaches a debugger, which is called before every statement
+    pub fn set_debugger(&mut self, debugger: Box<dyn DebugHook>) {
+        self.debugger = Some(debugger);
+        self.frames = vec![Frame {
+            function: String::from("script"),
+            line: 0,
+            environment: self.globals.clone(),
+        }];
+    }
+
+    // The calls in progress, innermost first. Empty unless a debugger is
+    // attached.
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| StackFrame {
+                function: frame.function.clone(),
+                line: frame.line,
+            })
+            .collect()
+    }
+
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // The variables visible in a frame of the call stack, one list per scope
+    // from the innermost out to the globals
+    pub fn frame_scopes(&self, frame: usize) -> Vec<Vec<(String, RoxType)>> {
+        let mut environment = if frame == 0 {
+            Some(self.environment.clone())
+        } else {
+            self.frames
+                .iter()
+                .rev()
+                .nth(frame)
+                .map(|frame| frame.environment.clone())
+        };
+        let mut scopes = Vec::new();
+        while let Some(scope) = environment {
+            let scope = scope.borrow();
+            let variables = scope
+                .names()
+                .into_iter()
+                .filter_map(|name| scope.get_local(Symbol::intern(&name)).map(|value| (name, value)))
+                .collect();
+            scopes.push(variables);
+            environment = scope.enclosing.clone();
         }
+        scopes
     }
 
     pub fn interpret(&mut self, statements: Vec<Stmt>) -> Result<(), InterpreterError> {
-        for val in statements {
-            self.execute(val)?;
+        for val in &statements {
+            match self.execute(val) {
+                Ok(()) => (),
+                Err(Unwind::Return(_)) => return Ok(()),
+                Err(Unwind::Error(e)) => return Err(self.script_error(e)),
+            }
         }
         Ok(())
     }
 
-    fn execute(&mut self, stmt: Stmt) -> Result<(), InterpreterError> {
+    // Like interpret, but a trailing expression statement is evaluated and
+    // its value handed back instead of being thrown away
+    pub fn interpret_value(
+        &mut self,
+        mut statements: Vec<Stmt>,
+    ) -> Result<Option<RoxType>, InterpreterError> {
+        let last = match statements.last() {
+            Some(Stmt::Expression(_)) => statements.pop(),
+            _ => None,
+        };
+        self.interpret(statements)?;
+        match last {
+            Some(Stmt::Expression(exp)) => match self.eval(&exp) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(self.script_error(e)),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn script_error(&mut self, mut e: InterpreterError) -> InterpreterError {
+        self.had_runtime_error = true;
+        e.stack.push(StackFrame {
+            function: String::from("script"),
+            line: e.line,
+        });
+        e
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> Result<(), Unwind> {
+        if self.debugger.is_some() {
+            if let Some(line) = stmt_line(stmt) {
+                self.debug_hook(line);
+            }
+        }
         match stmt {
+            Stmt::Block(statements) => {
+                let environment = Environment::with_enclosing(self.environment.clone());
+                self.execute_block(statements, Rc::new(RefCell::new(environment)))?;
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let superclass = match superclass {
+                    Some(superclass) => match self.eval(superclass)? {
+                        RoxType::Class(class) => Some(class),
+                        _ => {
+                            return Err(InterpreterError::at(name, "Superclass must be a class.")
+                            .into())
+                        }
+                    },
+                    None => None,
+                };
+
+                self.environment
+                    .borrow_mut()
+                    .define(name.lexeme.symbol(), RoxType::Nil);
+
+                // Methods of a subclass close over a scope holding 'super'
+                let previous = self.environment.clone();
+                if let Some(superclass) = &superclass {
+                    let mut environment = Environment::with_enclosing(previous.clone());
+                    environment.define(Symbol::SUPER, RoxType::Class(superclass.clone()));
+                    self.environment = Rc::new(RefCell::new(environment));
+                }
+
+                self.heap.track_environment(&self.environment);
+                let mut class_methods = SymbolMap::default();
+                for method in methods {
+                    if let Stmt::Function(method_name, params, body) = method {
+                        let is_initializer = method_name.lexeme == Symbol::INIT;
+                        let function = RoxFunction::new(
+                            method_name.clone(),
+                            params.clone(),
+                            body.clone(),
+                            self.environment.clone(),
+                            is_initializer,
+                        );
+                        class_methods.insert(method_name.lexeme.symbol(), Rc::new(function));
+                    }
+                }
+
+                self.environment = previous;
+                let class = RoxClass::new(name.lexeme.to_string(), superclass, class_methods);
+                self.environment
+                    .borrow_mut()
+                    .assign(name, RoxType::Class(Rc::new(class)))?;
+            }
             Stmt::Expression(exp) => {
-                self.eval(*exp)?;
+                self.eval(exp)?;
+            }
+            Stmt::Function(name, params, body) => {
+                self.heap.track_environment(&self.environment);
+                let function = RoxFunction::new(
+                    name.clone(),
+                    params.clone(),
+                    body.clone(),
+                    self.environment.clone(),
+                    false,
+                );
+                self.environment
+                    .borrow_mut()
+                    .define(name.lexeme.symbol(), RoxType::Callable(Rc::new(function)));
+            }
+            Stmt::If(_, condition, then_branch, else_branch) => {
+                if truthy(self.eval(condition)?) {
+                    self.execute(then_branch)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)?;
+                }
             }
-            Stmt::Print(exp) => {
-                let rt = self.eval(*exp)?;
-                println!("{:?}", rt);
+            Stmt::Print(keyword, exp) => {
+                let rt = self.eval(exp)?;
+                writeln!(self.output, "{}", rt)
+                    .map_err(|_| InterpreterError::at(keyword, "Could not write output."))?;
+            }
+            Stmt::Return(_, exp) => {
+                let value = match exp {
+                    Some(e) => self.eval(e)?,
+                    None => RoxType::Nil,
+                };
+                return Err(Unwind::Return(value));
             }
             Stmt::Var(tok, exp) => match exp {
                 Some(e) => {
-                    let rt = self.eval(*e)?;
-                    self.environment.define(tok, rt);
+                    let rt = self.eval(e)?;
+                    self.environment.borrow_mut().define(tok.lexeme.symbol(), rt);
                 }
-                None => self.environment.define(tok, RoxType::Nil),
+                None => self.environment.borrow_mut().define(tok.lexeme.symbol(), RoxType::Nil),
             },
+            Stmt::While(_, condition, body) => {
+                while truthy(self.eval(condition)?) {
+                    self.execute(body)?;
+                }
+            }
         }
         Ok(())
     }
 
-    fn eval(&mut self, expr: Expr) -> InterpreterResult {
+    // Hands control to the debugger before a statement on the given line
+    fn debug_hook(&mut self, line: u32) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.line = line;
+        }
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.on_statement(self, line);
+            self.debugger = Some(debugger);
+        }
+    }
+
+    fn enter_frame(&mut self, function: &str) {
+        if let Some(caller) = self.frames.last_mut() {
+            caller.environment = self.environment.clone();
+        }
+        self.frames.push(Frame {
+            function: format!("{}()", function),
+            line: 0,
+            environment: self.environment.clone(),
+        });
+    }
+
+    // Runs statements in the given scope, restoring the current one afterwards
+    // even if a statement fails
+    pub(crate) fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<(), Unwind> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let mut result = Ok(());
+        for stmt in statements {
+            result = self.execute(stmt);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.environment = previous;
+        result
+    }
+
+    fn eval(&mut self, expr: &Expr) -> InterpreterResult {
         match expr {
+            Expr::Assign(t, exp, depth) => {
+                let val = self.eval(exp)?;
+                match depth {
+                    Some(depth) => self.environment.borrow_mut().assign_at(*depth, t, val.clone())?,
+                    None => self.globals.borrow_mut().assign(t, val.clone())?,
+                }
+                Ok(val)
+            }
             Expr::Binary(lexp, t, rexp) => {
-                let lval = self.eval(*lexp)?;
-                let rval = self.eval(*rexp)?;
-                match t.token_type {
-                    TokenType::Minus => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Number(lnum - rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from("Invalid Types in Binary Minus Expr"),
-                        }),
-                    },
-                    TokenType::Plus => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Number(lnum - rnum))
-                        }
-                        (RoxType::String(lstr), RoxType::String(rstr)) => {
-                            let mut combined_string = lstr;
-                            combined_string.push_str(&rstr);
-                            Ok(RoxType::String(combined_string))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from("Invalid Types in Binary Addition Expr"),
-                        }),
-                    },
-                    TokenType::Slash => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Number(lnum / rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from("Invalid Types in Binary Division Expr"),
-                        }),
-                    },
-                    TokenType::Star => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Number(lnum * rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from("Invalid Types in Binary Multiply Expr"),
-                        }),
-                    },
-                    TokenType::Greater => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Boolean(lnum > rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from(
-                                "Invalid Types in Binary Greater Comparison Expr",
-                            ),
-                        }),
-                    },
-                    TokenType::GreaterEqual => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Boolean(lnum >= rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from(
-                                "Invalid Types in Binary GreaterEqual Comparison Expr",
-                            ),
-                        }),
-                    },
-                    TokenType::Less => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Boolean(lnum < rnum))
-                        }
-                        _ => Err(InterpreterError {
-                            error_string: String::from(
-                                "Invalid Types in Binary Less Comparison Expr",
-                            ),
-                        }),
-                    },
-                    TokenType::LessEqual => match (lval, rval) {
-                        (RoxType::Number(lnum), RoxType::Number(rnum)) => {
-                            Ok(RoxType::Boolean(lnum <= rnum))
+                let lval = self.eval(lexp)?;
+                let rval = self.eval(rexp)?;
+                binary(lval, t, rval)
+            }
+            Expr::Call(callee, paren, arguments) => {
+                let callee = self.eval(callee)?;
+
+                let mut args = Vec::new();
+                for argument in arguments {
+                    args.push(self.eval(argument)?);
+                }
+
+                let function: Rc<dyn Callable> = match callee {
+                    RoxType::Callable(function) => function,
+                    RoxType::Class(class) => class,
+                    _ => {
+                        return Err(InterpreterError::at(paren, "Can only call functions and classes."))
+                    }
+                };
+                if args.len() != function.arity() {
+                    return Err(InterpreterError::at(
+                        paren,
+                        &format!("Expected {} arguments but got {}.", function.arity(), args.len()),
+                    ));
+                }
+                if self.call_depth == MAX_CALL_DEPTH {
+                    return Err(InterpreterError::at(paren, "Stack overflow."));
+                }
+                let name = function.name();
+                let debugging = self.debugger.is_some();
+                if debugging {
+                    self.enter_frame(&name);
+                }
+                self.call_depth += 1;
+                let result = function.call(self, args).map_err(|mut e| {
+                    e.unwind_through(&name, paren);
+                    e
+                });
+                self.call_depth -= 1;
+                if debugging {
+                    self.frames.pop();
+                }
+                result
+            }
+            Expr::Get(object, name) => match self.eval(object)? {
+                RoxType::Instance(instance) => get_property(&instance, name),
+                _ => Err(InterpreterError::at(name, "Only instances have properties.")),
+            },
+            Expr::Grouping(exp) => self.eval(exp),
+            Expr::Set(object, name, value) => {
+                let RoxType::Instance(instance) = self.eval(object)? else {
+                    return Err(InterpreterError::at(name, "Only instances have fields."));
+                };
+                let value = self.eval(value)?;
+                instance.borrow_mut().set(name, value.clone());
+                Ok(value)
+            }
+            Expr::CompoundSet(object, name, operator, value) => {
+                let RoxType::Instance(instance) = self.eval(object)? else {
+                    return Err(InterpreterError::at(name, "Only instances have properties."));
+                };
+                let current = get_property(&instance, name)?;
+                let value = binary(current, operator, self.eval(value)?)?;
+                instance.borrow_mut().set(name, value.clone());
+                Ok(value)
+            }
+            Expr::Postfix(target, operator) => {
+                let one = RoxType::Number(1.0);
+                match target.as_ref() {
+                    Expr::Variable(name, depth) => {
+                        let old = self.eval(target)?;
+                        let new = binary(old.clone(), operator, one)?;
+                        match depth {
+                            Some(depth) => self.environment.borrow_mut().assign_at(*depth, name, new)?,
+                            None => self.globals.borrow_mut().assign(name, new)?,
                         }
-                        _ => Err(InterpreterError {
-                            error_string: String::from(
-                                "Invalid Types in Binary LessEqual Comparison Expr",
-                            ),
-                        }),
-                    },
-                    TokenType::BangEqual => Ok(RoxType::Boolean(isEqual(lval, rval))),
-                    _ => panic!("Invalid Expression, parser panic"),
+                        Ok(old)
+                    }
+                    Expr::Get(object, name) => {
+                        let RoxType::Instance(instance) = self.eval(object)? else {
+                            return Err(InterpreterError::at(name, "Only instances have properties."));
+                        };
+                        let old = get_property(&instance, name)?;
+                        let new = binary(old.clone(), operator, one)?;
+                        instance.borrow_mut().set(name, new);
+                        Ok(old)
+                    }
+                    _ => panic!("the parser only steps variables and properties"),
+                }
+            }
+            Expr::Super(keyword, method, depth) => {
+                let depth = depth.expect("resolver binds 'super' to a local scope");
+                let superclass = self.environment.borrow().get_at(depth, keyword)?;
+                // 'this' is always bound in the scope just inside 'super'
+                let object = self
+                    .environment
+                    .borrow()
+                    .get_local_at(depth - 1, Symbol::THIS)
+                    .expect("'this' is bound inside the 'super' scope");
+                let (RoxType::Class(superclass), RoxType::Instance(instance)) = (superclass, object)
+                else {
+                    panic!("'super' and 'this' bound to unexpected values");
+                };
+                match superclass.find_method(method.lexeme.symbol()) {
+                    Some(method) => Ok(RoxType::Callable(Rc::new(method.bind(instance)))),
+                    None => Err(InterpreterError::at(method, &format!("Undefined property '{}'.", method.lexeme))),
+                }
+            }
+            Expr::This(keyword, depth) => match depth {
+                Some(depth) => self.environment.borrow().get_at(*depth, keyword),
+                None => self.globals.borrow().get(keyword),
+            },
+            Expr::Variable(t, depth) => match depth {
+                Some(depth) => self.environment.borrow().get_at(*depth, t),
+                None => self.globals.borrow().get(t),
+            },
+            Expr::Literal(rtype) => Ok(rtype.clone()),
+            // Short-circuits, yielding whichever operand decided the result
+            Expr::Logical(lexp, t, rexp) => {
+                let lval = self.eval(lexp)?;
+                match t.token_type {
+                    TokenType::Or if truthy(lval.clone()) => Ok(lval),
+                    TokenType::And if !truthy(lval.clone()) => Ok(lval),
+                    _ => self.eval(rexp),
                 }
             }
-            Expr::Grouping(exp) => self.eval(*exp),
-            Expr::Literal(rtype) => Ok(rtype),
             Expr::Unary(t, exp) => {
-                let eval = self.eval(*exp)?;
+                let eval = self.eval(exp)?;
                 match t.token_type {
                     TokenType::Bang => Ok(RoxType::Boolean(!truthy(eval))),
                     TokenType::Minus => match eval {
                         RoxType::Number(num) => Ok(RoxType::Number(-num)),
-                        _ => Err(InterpreterError {
-                            error_string: String::from("Unary Expression must be number"),
-                        }),
+                        _ => Err(InterpreterError::at(t, "Operand must be a number.")),
                     },
-                    _ => Err(InterpreterError {
-                        error_string: String::from("Invalid unary expression"),
-                    }),
+                    _ => Err(InterpreterError::at(t, "Invalid unary expression.")),
                 }
             }
         }
     }
 }
 
+// The line a statement starts on, if it has a token to tell; blocks and
+// bare literals don't
+fn stmt_line(stmt: &Stmt) -> Option<u32> {
+    match stmt {
+        Stmt::Block(_) => None,
+        Stmt::Class(name, ..) | Stmt::Function(name, ..) | Stmt::Var(name, _) => Some(name.span.line),
+        Stmt::If(keyword, ..)
+        | Stmt::Print(keyword, _)
+        | Stmt::Return(keyword, _)
+        | Stmt::While(keyword, ..) => Some(keyword.span.line),
+        Stmt::Expression(exp) => expr_line(exp),
+    }
+}
+
+// The lines a debugger can stop on: those where a statement that gets
+// executed starts. Method declarations aren't executed, only their bodies.
+pub fn statement_lines(statements: &[Stmt]) -> BTreeSet<u32> {
+    let mut lines = BTreeSet::new();
+    add_statement_lines(statements, &mut lines);
+    lines
+}
+
+fn add_statement_lines(statements: &[Stmt], lines: &mut BTreeSet<u32>) {
+    for stmt in statements {
+        lines.extend(stmt_line(stmt));
+        match stmt {
+            Stmt::Block(body) => add_statement_lines(body, lines),
+            Stmt::Function(_, _, body) => add_statement_lines(body, lines),
+            Stmt::Class(_, _, methods) => {
+                for method in methods {
+                    if let Stmt::Function(_, _, body) = method {
+                        add_statement_lines(body, lines);
+                    }
+                }
+            }
+            Stmt::If(_, _, then_branch, else_branch) => {
+                add_statement_lines(std::slice::from_ref(then_branch), lines);
+                if let Some(else_branch) = else_branch {
+                    add_statement_lines(std::slice::from_ref(else_branch), lines);
+                }
+            }
+            Stmt::While(_, _, body) => add_statement_lines(std::slice::from_ref(body), lines),
+            Stmt::Expression(_) | Stmt::Print(..) | Stmt::Return(..) | Stmt::Var(..) => {}
+        }
+    }
+}
+
+fn expr_line(expr: &Expr) -> Option<u32> {
+    match expr {
+        Expr::Assign(name, ..) | Expr::Variable(name, _) => Some(name.span.line),
+        Expr::Super(keyword, ..) | Expr::This(keyword, _) | Expr::Unary(keyword, _) => {
+            Some(keyword.span.line)
+        }
+        Expr::Binary(left, operator, _) | Expr::Logical(left, operator, _) => {
+            expr_line(left).or(Some(operator.span.line))
+        }
+        Expr::Call(callee, paren, _) => expr_line(callee).or(Some(paren.span.line)),
+        Expr::Get(object, name)
+        | Expr::Set(object, name, _)
+        | Expr::CompoundSet(object, name, ..) => {
+            expr_line(object).or(Some(name.span.line))
+        }
+        Expr::Grouping(exp) | Expr::Postfix(exp, _) => expr_line(exp),
+        Expr::Literal(_) => None,
+    }
+}
+
+// Applies a binary operator to operands that have already been evaluated
+fn binary(lval: RoxType, t: &Token, rval: RoxType) -> InterpreterResult {
+    match t.token_type {
+        TokenType::Minus => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Number(lnum - rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::Plus => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Number(lnum + rnum))
+            }
+            (RoxType::String(lstr), RoxType::String(rstr)) => {
+                let mut combined_string = lstr;
+                combined_string.push_str(&rstr);
+                Ok(RoxType::String(combined_string))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be two numbers or two strings.")),
+        },
+        TokenType::Slash => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Number(lnum / rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::Star => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Number(lnum * rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::Greater => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Boolean(lnum > rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::GreaterEqual => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Boolean(lnum >= rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::Less => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Boolean(lnum < rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::LessEqual => match (lval, rval) {
+            (RoxType::Number(lnum), RoxType::Number(rnum)) => {
+                Ok(RoxType::Boolean(lnum <= rnum))
+            }
+            _ => Err(InterpreterError::at(t, "Operands must be numbers.")),
+        },
+        TokenType::BangEqual => Ok(RoxType::Boolean(!is_equal(lval, rval))),
+        TokenType::EqualEqual => Ok(RoxType::Boolean(is_equal(lval, rval))),
+        _ => panic!("Invalid Expression, parser panic"),
+    }
+}
+
 fn truthy(val: RoxType) -> bool {
     match val {
         RoxType::Boolean(b) => b,
@@ -199,13 +931,68 @@ fn truthy(val: RoxType) -> bool {
     }
 }
 
-fn isEqual(lval: RoxType, rval: RoxType) -> bool {
+fn is_equal(lval: RoxType, rval: RoxType) -> bool {
     match (lval, rval) {
         (RoxType::String(lstr), RoxType::String(rstr)) => lstr == rstr,
         (RoxType::Nil, RoxType::Nil) => true,
         (RoxType::Nil, _) => false,
         (RoxType::Number(lnum), RoxType::Number(rnum)) => lnum == rnum,
         (RoxType::Boolean(lbool), RoxType::Boolean(rbool)) => lbool == rbool,
+        (RoxType::Callable(lfun), RoxType::Callable(rfun)) => Rc::ptr_eq(&lfun, &rfun),
+        (RoxType::Class(lclass), RoxType::Class(rclass)) => Rc::ptr_eq(&lclass, &rclass),
+        (RoxType::Instance(linst), RoxType::Instance(rinst)) => Rc::ptr_eq(&linst, &rinst),
         (_, _) => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(function: &str, line: u32) -> StackFrame {
+        StackFrame {
+            function: String::from(function),
+            line,
+        }
+    }
+
+    #[test]
+    fn keeps_short_tracebacks() {
+        let stack = [frame("f()", 2), frame("g()", 5), frame("script", 7)];
+        assert_eq!(
+            traceback(&stack),
+            ["[line 2] in f()", "[line 5] in g()", "[line 7] in script"]
+        );
+    }
+
+    #[test]
+    fn collapses_recursion() {
+        let mut stack = vec![frame("f()", 1)];
+        stack.extend((0..5000).map(|_| frame("f()", 2)));
+        stack.push(frame("script", 4));
+        assert_eq!(
+            traceback(&stack),
+            [
+                "[line 1] in f()",
+                "[line 2] in f()",
+                "... 4999 more frames in f()",
+                "[line 4] in script"
+            ]
+        );
+    }
+
+    #[test]
+    fn shows_the_ends_of_long_tracebacks() {
+        let mut stack = Vec::new();
+        for _ in 0..100 {
+            stack.push(frame("a()", 1));
+            stack.push(frame("b()", 2));
+        }
+        stack.push(frame("script", 3));
+        let lines = traceback(&stack);
+        assert_eq!(lines.len(), 2 * TRACEBACK_ENDS + 1);
+        assert_eq!(lines[0], "[line 1] in a()");
+        assert_eq!(lines[TRACEBACK_ENDS], format!("... {} more frames", 201 - 2 * TRACEBACK_ENDS));
+        assert_eq!(lines[lines.len() - 1], "[line 3] in script");
+    }
+}
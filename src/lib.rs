@@ -0,0 +1,233 @@
+pub mod analysis;
+pub mod ast_json;
+pub mod ast_printer;
+pub mod callable;
+pub mod chunk;
+pub mod class;
+pub mod compiler;
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
+mod error;
+pub mod formatter;
+pub mod gc;
+pub mod interpreter;
+pub mod json;
+pub mod lsp;
+mod natives;
+pub mod parser;
+pub mod resolver;
+pub mod rox_type;
+pub mod roxc;
+mod rpc;
+pub mod scanner;
+pub mod symbol;
+pub mod token;
+pub mod token_type;
+pub mod vm;
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+pub use diagnostic::Diagnostic;
+pub use error::RoxError;
+pub use interpreter::{Interpreter, InterpreterError, InterpreterResult};
+pub use rox_type::RoxType;
+
+use chunk::Program;
+use parser::{Parser, Stmt};
+use resolver::Resolver;
+use scanner::Scanner;
+use token::Token;
+use token_type::TokenType;
+
+#[macro_use]
+extern crate lazy_static;
+
+// Embedding entry point: an interpreter whose globals persist between calls
+#[derive(Debug, Default)]
+pub struct Rox {
+    interpreter: Interpreter,
+}
+
+impl Rox {
+    pub fn new() -> Rox {
+        Rox {
+            interpreter: Interpreter::new(),
+        }
+    }
+
+    // Scans, parses, resolves and runs a piece of source code
+    pub fn eval(&mut self, code: &str) -> Result<(), RoxError> {
+        let statements = Rox::compile(code)?;
+        self.interpreter
+            .interpret(statements)
+            .map_err(|e| RoxError::Runtime(e.locate(code)))
+    }
+
+    // Runs a line typed at a prompt and returns the value of a trailing bare
+    // expression. The final ';' may be left off.
+    pub fn eval_line(&mut self, code: &str) -> Result<Option<RoxType>, RoxError> {
+        let statements = match Rox::compile(code) {
+            Err(RoxError::Parse(diagnostics)) => {
+                Rox::compile(&format!("{}\n;", code)).map_err(|_| RoxError::Parse(diagnostics))?
+            }
+            compiled => compiled?,
+        };
+        self.interpreter
+            .interpret_value(statements)
+            .map_err(|e| RoxError::Runtime(e.locate(code)))
+    }
+
+    // Scans, parses and resolves without running anything
+    pub fn compile(code: &str) -> Result<Vec<Stmt>, RoxError> {
+        let mut statements = Rox::parse(code)?;
+        Resolver::new().resolve(&mut statements).map_err(|errs| {
+            let diagnostics = errs
+                .iter()
+                .map(|e| Diagnostic::at_token(code, &e.err_token, &e.err_msg, "not allowed here"))
+                .collect();
+            RoxError::Resolve(diagnostics)
+        })?;
+        Ok(statements)
+    }
+
+    // Compiles all the way to bytecode for the VM backend
+    pub fn compile_bytecode(code: &str) -> Result<Program, RoxError> {
+        let statements = Rox::compile(code)?;
+        compiler::compile(&statements).map_err(|errs| {
+            let diagnostics = errs
+                .iter()
+                .map(|e| Diagnostic::at_token(code, &e.err_token, &e.err_msg, "limit reached here"))
+                .collect();
+            RoxError::Resolve(diagnostics)
+        })
+    }
+
+    // Scans and parses, leaving variables unresolved
+    pub fn parse(code: &str) -> Result<Vec<Stmt>, RoxError> {
+        let mut scanner = Scanner::new(code);
+        let tokens = scanner.scan_tokens();
+
+        let mut diagnostics = scanner.errors().to_vec();
+
+        let mut parser = Parser::new(tokens);
+        let parsed = parser.parse();
+        if let Err(errs) = &parsed {
+            diagnostics.extend(errs.iter().map(|e| {
+                Diagnostic::at_token(code, &e.err_token, &e.err_msg, &found(&e.err_token))
+            }));
+        }
+        if !diagnostics.is_empty() {
+            diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+            return Err(RoxError::Parse(diagnostics));
+        }
+        Ok(parsed.unwrap_or_default())
+    }
+
+    pub fn run_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RoxError> {
+        let code = read_to_string(path)?;
+        self.eval(&code)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<RoxType> {
+        self.interpreter.get_global(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: RoxType) {
+        self.interpreter.set_global(name, value)
+    }
+
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[RoxType]) -> InterpreterResult + 'static,
+    {
+        self.interpreter.define_native(name, arity, function)
+    }
+
+    // Exposes command-line arguments to the script through argc() and argv(n)
+    pub fn set_args(&mut self, args: Vec<String>) {
+        natives::define_args(&mut self.interpreter, args)
+    }
+
+    pub fn interpreter(&mut self) -> &mut Interpreter {
+        &mut self.interpreter
+    }
+}
+
+// Caret label for a parse error: what the parser ran into
+fn found(token: &Token) -> String {
+    match token.token_type {
+        TokenType::EOF => String::from("found end of input"),
+        _ => format!("found '{}'", token.lexeme),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The value a line evaluates to, as the prompt would print it
+    fn eval_line(rox: &mut Rox, code: &str) -> Option<String> {
+        let value = rox.eval_line(code).unwrap_or_else(|e| panic!("{}: {}", code, e));
+        value.map(|value| value.to_string())
+    }
+
+    fn prompt() -> Rox {
+        let mut rox = Rox::new();
+        rox.interpreter().set_output(Box::new(std::io::sink()));
+        rox
+    }
+
+    #[test]
+    fn bare_expressions_have_a_value() {
+        let mut rox = prompt();
+        assert_eq!(eval_line(&mut rox, "1 + 2\n").as_deref(), Some("3"));
+        assert_eq!(eval_line(&mut rox, "1 + 2;\n").as_deref(), Some("3"));
+        assert_eq!(eval_line(&mut rox, "\"a\" + \"b\"").as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn statements_have_none_and_the_semicolon_is_optional() {
+        let mut rox = prompt();
+        assert_eq!(eval_line(&mut rox, "var a = 1;\n"), None);
+        assert_eq!(eval_line(&mut rox, "var b = 2\n"), None);
+        assert_eq!(eval_line(&mut rox, "print a + b\n"), None);
+        assert_eq!(eval_line(&mut rox, "fun add(x, y) { return x + y; }\n"), None);
+        // Globals carry over from line to line
+        assert_eq!(eval_line(&mut rox, "add(a, b)\n").as_deref(), Some("3"));
+        assert_eq!(eval_line(&mut rox, "a = 5; a * 2\n").as_deref(), Some("10"));
+    }
+
+    #[test]
+    fn syntax_errors_are_reported_against_the_line_as_typed() {
+        let mut rox = prompt();
+        let error = rox.eval_line("1 +\n").unwrap_err();
+        assert!(matches!(error, RoxError::Parse(_)));
+        let error = error.to_string();
+        assert!(error.contains("Expect expression."), "{}", error);
+        // The retry with a ';' added isn't what's shown
+        assert!(!error.contains("at ';'"), "{}", error);
+        assert!(matches!(rox.eval_line("var = 1\n"), Err(RoxError::Parse(_))));
+    }
+
+    #[test]
+    fn compound_operator_errors_underline_the_operator_they_name() {
+        let cases = [
+            ("var x = 1;\nx += \"a\";\n", "2 | x += \"a\";\n  |   ^ raised here"),
+            ("var x = \"a\";\nx++;\n", "2 | x++;\n  |  ^ raised here"),
+        ];
+        for (code, caret) in cases {
+            let error = prompt().eval(code).unwrap_err().to_string();
+            assert!(error.starts_with("[line 2] Error at '+':"), "{}", error);
+            assert!(error.contains(caret), "{}", error);
+        }
+    }
+
+    #[test]
+    fn runtime_errors_leave_the_session_usable() {
+        let mut rox = prompt();
+        assert!(matches!(rox.eval_line("nil + 1\n"), Err(RoxError::Runtime(_))));
+        assert_eq!(eval_line(&mut rox, "2 * 3\n").as_deref(), Some("6"));
+    }
+}
@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::analysis::{Analysis, Symbol, SymbolKind};
+use crate::diagnostic::Diagnostic;
+use crate::error::RoxError;
+use crate::json::Json;
+use crate::rpc::{read_message, write_message};
+use crate::scanner::IDENTIFIERS;
+use crate::token::Span;
+use crate::{Interpreter, Rox};
+
+// JSON-RPC error codes
+const INVALID_REQUEST: f64 = -32600.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INVALID_PARAMS: f64 = -32602.0;
+
+// Language server speaking LSP over a pair of streams, normally stdin and
+// stdout. Documents are synced in full on every change and analysed on
+// demand.
+pub struct Server {
+    documents: HashMap<String, Document>,
+    // Natives every script can call
+    builtins: Vec<String>,
+    shutdown: bool,
+}
+
+struct Document {
+    text: String,
+    // Byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            documents: HashMap::new(),
+            builtins: Interpreter::new().global_names(),
+            shutdown: false,
+        }
+    }
+
+    // Serves until the client sends exit or closes the input, and returns
+    // the process exit code
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<i32> {
+        while let Some(message) = read_message(&mut input)? {
+            let message = match Json::parse(&message) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+            if method == "exit" {
+                return Ok(if self.shutdown { 0 } else { 1 });
+            }
+            let params = message.get("params").cloned().unwrap_or(Json::Null);
+            let outgoing = match message.get("id") {
+                Some(id) => vec![self.request(id, method, &params)],
+                None => self.notification(method, &params),
+            };
+            for reply in outgoing {
+                write_message(&mut output, &reply)?;
+            }
+        }
+        Ok(1)
+    }
+
+    fn request(&mut self, id: &Json, method: &str, params: &Json) -> Json {
+        if self.shutdown {
+            return error_response(id, INVALID_REQUEST, "Server is shutting down");
+        }
+        let result = match method {
+            "initialize" => Ok(initialize_result()),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            "textDocument/completion" => self.completion(params),
+            _ => {
+                let message = format!("Unknown method '{}'", method);
+                return error_response(id, METHOD_NOT_FOUND, &message);
+            }
+        };
+        match result {
+            Ok(result) => Json::object(vec![
+                ("jsonrpc", Json::string("2.0")),
+                ("id", id.clone()),
+                ("result", result),
+            ]),
+            Err(message) => error_response(id, INVALID_PARAMS, &message),
+        }
+    }
+
+    // Returns the messages to send back
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .map(String::from);
+        let Some(uri) = uri else {
+            return Vec::new();
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|document| document.get("text"))
+                    .and_then(Json::as_str)
+                    .unwrap_or("");
+                self.documents.insert(uri.clone(), Document::new(text));
+            }
+            "textDocument/didChange" => {
+                // Full sync, so the last change holds the whole text
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), Document::new(text));
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, Vec::new())];
+            }
+            _ => return Vec::new(),
+        }
+        let diagnostics = match self.documents.get(&uri) {
+            Some(document) => document.diagnostics(),
+            None => Vec::new(),
+        };
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    fn hover(&self, params: &Json) -> Result<Json, String> {
+        let (_, document, offset) = self.position(params)?;
+        let analysis = Analysis::new(&document.text);
+        let Some(symbol) = analysis.symbol_at(offset) else {
+            return Ok(Json::Null);
+        };
+        let value = format!(
+            "```lox\n{}\n```\n{}",
+            symbol.detail,
+            symbol.kind.describe()
+        );
+        Ok(Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![
+                    ("kind", Json::string("markdown")),
+                    ("value", Json::string(&value)),
+                ]),
+            ),
+            ("range", document.range(symbol_span_at(symbol, offset))),
+        ]))
+    }
+
+    fn definition(&self, params: &Json) -> Result<Json, String> {
+        let (uri, document, offset) = self.position(params)?;
+        let analysis = Analysis::new(&document.text);
+        Ok(match analysis.symbol_at(offset) {
+            Some(symbol) => location(uri, document, symbol.declaration),
+            None => Json::Null,
+        })
+    }
+
+    fn references(&self, params: &Json) -> Result<Json, String> {
+        let (uri, document, offset) = self.position(params)?;
+        let analysis = Analysis::new(&document.text);
+        let Some(symbol) = analysis.symbol_at(offset) else {
+            return Ok(Json::Array(Vec::new()));
+        };
+        let include_declaration = params
+            .get("context")
+            .and_then(|context| context.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+        let mut locations = Vec::new();
+        if include_declaration {
+            locations.push(location(uri, document, symbol.declaration));
+        }
+        for span in &symbol.references {
+            locations.push(location(uri, document, *span));
+        }
+        Ok(Json::Array(locations))
+    }
+
+    fn document_symbols(&self, params: &Json) -> Result<Json, String> {
+        let (_, document) = self.document(params)?;
+        let analysis = Analysis::new(&document.text);
+        let symbols = analysis
+            .symbols
+            .iter()
+            .enumerate()
+            .filter(|(_, symbol)| symbol.global)
+            .map(|(id, symbol)| {
+                let children = analysis
+                    .methods(id)
+                    .map(|method| document_symbol(document, method, Vec::new()))
+                    .collect();
+                document_symbol(document, symbol, children)
+            })
+            .collect();
+        Ok(Json::Array(symbols))
+    }
+
+    fn completion(&self, params: &Json) -> Result<Json, String> {
+        let (_, document, offset) = self.position(params)?;
+        let analysis = Analysis::new(&document.text);
+
+        // After a dot only properties make sense, and methods are the ones
+        // that can be known ahead of time
+        let before = document.text[..offset].trim_end_matches(is_identifier_char);
+        if before.ends_with('.') {
+            let mut names = analysis
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.kind == SymbolKind::Method)
+                .map(|symbol| symbol.name.as_str())
+                .collect::<Vec<&str>>();
+            names.sort();
+            names.dedup();
+            let items = names
+                .into_iter()
+                .map(|name| completion_item(name, METHOD_ITEM, "method"))
+                .collect();
+            return Ok(Json::Array(items));
+        }
+
+        let mut items = Vec::new();
+        for symbol in analysis.visible_at(offset) {
+            items.push(completion_item(
+                &symbol.name,
+                completion_kind(symbol.kind),
+                &symbol.detail,
+            ));
+        }
+        for builtin in &self.builtins {
+            items.push(completion_item(builtin, FUNCTION_ITEM, "native function"));
+        }
+        let mut keywords = IDENTIFIERS.keys().collect::<Vec<&&str>>();
+        keywords.sort();
+        for keyword in keywords {
+            items.push(completion_item(keyword, KEYWORD_ITEM, "keyword"));
+        }
+        Ok(Json::Array(items))
+    }
+
+    fn document(&self, params: &Json) -> Result<(&str, &Document), String> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .ok_or("Missing textDocument.uri")?;
+        let (uri, document) = self
+            .documents
+            .get_key_value(uri)
+            .ok_or_else(|| format!("Unknown document {}", uri))?;
+        Ok((uri, document))
+    }
+
+    // The document and byte offset a text document position request is about
+    fn position(&self, params: &Json) -> Result<(&str, &Document, usize), String> {
+        let (uri, document) = self.document(params)?;
+        let position = params.get("position").ok_or("Missing position")?;
+        let line = position.get("line").and_then(Json::as_f64);
+        let character = position.get("character").and_then(Json::as_f64);
+        match (line, character) {
+            (Some(line), Some(character)) => {
+                Ok((uri, document, document.offset(line as usize, character as usize)))
+            }
+            _ => Err(String::from("Invalid position")),
+        }
+    }
+}
+
+impl Document {
+    fn new(text: &str) -> Document {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        Document {
+            text: String::from(text),
+            line_starts,
+        }
+    }
+
+    // Scanner, parser and resolver errors in LSP form
+    fn diagnostics(&self) -> Vec<Json> {
+        let diagnostics = match Rox::compile(&self.text) {
+            Err(RoxError::Parse(diagnostics) | RoxError::Resolve(diagnostics)) => diagnostics,
+            _ => Vec::new(),
+        };
+        diagnostics
+            .iter()
+            .map(|diagnostic| self.diagnostic(diagnostic))
+            .collect()
+    }
+
+    fn diagnostic(&self, diagnostic: &Diagnostic) -> Json {
+        Json::object(vec![
+            ("range", self.range(diagnostic.span)),
+            ("severity", Json::Number(SEVERITY_ERROR)),
+            ("source", Json::string("rox")),
+            (
+                "message",
+                Json::string(&format!("{} ({})", diagnostic.message, diagnostic.label)),
+            ),
+        ])
+    }
+
+    // LSP counts characters in UTF-16 code units
+    fn offset(&self, line: usize, character: usize) -> usize {
+        let Some(&start) = self.line_starts.get(line) else {
+            return self.text.len();
+        };
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        let mut units = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if units >= character {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        end
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.text[start..offset].encode_utf16().count();
+        Json::object(vec![
+            ("line", Json::Number(line as f64)),
+            ("character", Json::Number(character as f64)),
+        ])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object(vec![
+            ("start", self.position(span.start)),
+            ("end", self.position(span.end)),
+        ])
+    }
+}
+
+// LSP kinds
+const SEVERITY_ERROR: f64 = 1.0;
+const METHOD_ITEM: f64 = 2.0;
+const FUNCTION_ITEM: f64 = 3.0;
+const VARIABLE_ITEM: f64 = 6.0;
+const CLASS_ITEM: f64 = 7.0;
+const KEYWORD_ITEM: f64 = 14.0;
+
+fn initialize_result() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                // Full document sync
+                ("textDocumentSync", Json::Number(1.0)),
+                ("hoverProvider", Json::Bool(true)),
+                ("definitionProvider", Json::Bool(true)),
+                ("referencesProvider", Json::Bool(true)),
+                ("documentSymbolProvider", Json::Bool(true)),
+                (
+                    "completionProvider",
+                    Json::object(vec![(
+                        "triggerCharacters",
+                        Json::Array(vec![Json::string(".")]),
+                    )]),
+                ),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![
+                ("name", Json::string("rox")),
+                ("version", Json::string(env!("CARGO_PKG_VERSION"))),
+            ]),
+        ),
+    ])
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", Json::string(uri)),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+fn error_response(id: &Json, code: f64, message: &str) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("id", id.clone()),
+        (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(code)),
+                ("message", Json::string(message)),
+            ]),
+        ),
+    ])
+}
+
+fn location(uri: &str, document: &Document, span: Span) -> Json {
+    Json::object(vec![
+        ("uri", Json::string(uri)),
+        ("range", document.range(span)),
+    ])
+}
+
+fn document_symbol(document: &Document, symbol: &Symbol, children: Vec<Json>) -> Json {
+    let kind = match symbol.kind {
+        SymbolKind::Class => 5.0,
+        SymbolKind::Method => 6.0,
+        SymbolKind::Function => 12.0,
+        SymbolKind::Variable | SymbolKind::Parameter => 13.0,
+    };
+    Json::object(vec![
+        ("name", Json::string(&symbol.name)),
+        ("detail", Json::string(&symbol.detail)),
+        ("kind", Json::Number(kind)),
+        ("range", document.range(symbol.declaration)),
+        ("selectionRange", document.range(symbol.declaration)),
+        ("children", Json::Array(children)),
+    ])
+}
+
+fn completion_item(label: &str, kind: f64, detail: &str) -> Json {
+    Json::object(vec![
+        ("label", Json::string(label)),
+        ("kind", Json::Number(kind)),
+        ("detail", Json::string(detail)),
+    ])
+}
+
+fn completion_kind(kind: SymbolKind) -> f64 {
+    match kind {
+        SymbolKind::Variable | SymbolKind::Parameter => VARIABLE_ITEM,
+        SymbolKind::Function => FUNCTION_ITEM,
+        SymbolKind::Class => CLASS_ITEM,
+        SymbolKind::Method => METHOD_ITEM,
+    }
+}
+
+// The declaration or use of a symbol that covers an offset
+fn symbol_span_at(symbol: &Symbol, offset: usize) -> Span {
+    std::iter::once(&symbol.declaration)
+        .chain(&symbol.references)
+        .find(|span| span.start <= offset && offset <= span.end)
+        .copied()
+        .unwrap_or(symbol.declaration)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///test.lox";
+
+    // A global shadowed by a parameter, and a method used through 'this'
+    // and through an instance
+    const DOCUMENT: &str = "var count = 1;
+fun show(count) {
+  print count;
+}
+class Greeter {
+  greet(name) { return \"hi \" + name; }
+  twice(name) { return this.greet(name) + this.greet(name); }
+}
+var g = Greeter();
+print g.greet(\"x\");
+show(count);
+";
+
+    fn message(id: Option<f64>, method: &str, params: Json) -> Json {
+        let mut fields = vec![("jsonrpc", Json::string("2.0"))];
+        if let Some(id) = id {
+            fields.push(("id", Json::Number(id)));
+        }
+        fields.push(("method", Json::string(method)));
+        fields.push(("params", params));
+        Json::object(fields)
+    }
+
+    fn open(text: &str) -> Json {
+        let document = Json::object(vec![
+            ("uri", Json::string(URI)),
+            ("languageId", Json::string("lox")),
+            ("version", Json::Number(1.0)),
+            ("text", Json::string(text)),
+        ]);
+        message(None, "textDocument/didOpen", Json::object(vec![("textDocument", document)]))
+    }
+
+    fn at(id: f64, method: &str, line: usize, character: usize) -> Json {
+        let params = Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::string(URI))])),
+            (
+                "position",
+                Json::object(vec![
+                    ("line", Json::Number(line as f64)),
+                    ("character", Json::Number(character as f64)),
+                ]),
+            ),
+        ]);
+        message(Some(id), method, params)
+    }
+
+    // Runs a session over byte buffers and returns the exit code and what
+    // the server sent
+    fn session(messages: &[Json]) -> (i32, Vec<Json>) {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        let code = Server::new().run(input.as_slice(), &mut output).unwrap();
+        let mut output = output.as_slice();
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut output).unwrap() {
+            replies.push(Json::parse(&reply).unwrap());
+        }
+        (code, replies)
+    }
+
+    // The result of each request, by id, after opening DOCUMENT
+    fn results(requests: &[Json]) -> Vec<Json> {
+        let mut messages = vec![open(DOCUMENT)];
+        messages.extend_from_slice(requests);
+        let (_, replies) = session(&messages);
+        (1..=requests.len())
+            .map(|id| {
+                let reply = replies
+                    .iter()
+                    .find(|reply| reply.get("id") == Some(&Json::Number(id as f64)))
+                    .expect("every request is answered");
+                reply.get("result").cloned().expect("the request succeeds")
+            })
+            .collect()
+    }
+
+    // (line, character) of the start of a location or range
+    fn start(location: &Json) -> (f64, f64) {
+        let range = location.get("range").unwrap_or(location);
+        let start = range.get("start").unwrap();
+        (
+            start.get("line").and_then(Json::as_f64).unwrap(),
+            start.get("character").and_then(Json::as_f64).unwrap(),
+        )
+    }
+
+    fn starts(locations: &Json) -> Vec<(f64, f64)> {
+        locations.as_array().unwrap().iter().map(start).collect()
+    }
+
+    // A string field of each item in an array
+    fn fields<'a>(items: &'a Json, key: &str) -> Vec<&'a str> {
+        items
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|item| item.get(key).and_then(Json::as_str))
+            .collect()
+    }
+
+    #[test]
+    fn shuts_down_cleanly() {
+        let (code, replies) = session(&[
+            message(Some(1.0), "initialize", Json::object(Vec::new())),
+            message(Some(2.0), "shutdown", Json::Null),
+            message(None, "exit", Json::Null),
+        ]);
+        assert_eq!(code, 0);
+        let capabilities = replies[0].get("result").unwrap().get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+        assert_eq!(replies[1].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn publishes_diagnostics() {
+        let (_, replies) = session(&[open("print 1 +;\n"), open(DOCUMENT)]);
+        let diagnostics = |reply: &Json| {
+            let params = reply.get("params").unwrap();
+            assert_eq!(params.get("uri").and_then(Json::as_str), Some(URI));
+            params.get("diagnostics").unwrap().as_array().unwrap().to_vec()
+        };
+        let errors = diagnostics(&replies[0]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(start(&errors[0]), (0.0, 9.0));
+        assert!(diagnostics(&replies[1]).is_empty());
+    }
+
+    #[test]
+    fn hovers_over_the_innermost_declaration() {
+        let results = results(&[
+            at(1.0, "textDocument/hover", 2, 9),
+            at(2.0, "textDocument/hover", 10, 6),
+            at(3.0, "textDocument/hover", 9, 9),
+            at(4.0, "textDocument/hover", 3, 0),
+        ]);
+        let contents = |hover: &Json| {
+            let contents = hover.get("contents").unwrap();
+            contents.get("value").and_then(Json::as_str).unwrap().to_string()
+        };
+        assert_eq!(contents(&results[0]), "```lox\nparameter count\n```\nparameter");
+        assert_eq!(start(&results[0]), (2.0, 8.0));
+        assert_eq!(contents(&results[1]), "```lox\nvar count\n```\nvariable");
+        assert_eq!(contents(&results[2]), "```lox\nGreeter.greet(name)\n```\nmethod");
+        assert_eq!(results[3], Json::Null);
+    }
+
+    #[test]
+    fn finds_definitions() {
+        let results = results(&[
+            at(1.0, "textDocument/definition", 2, 9),
+            at(2.0, "textDocument/definition", 10, 6),
+            at(3.0, "textDocument/definition", 9, 9),
+            at(4.0, "textDocument/definition", 6, 28),
+            at(5.0, "textDocument/definition", 8, 10),
+        ]);
+        assert_eq!(start(&results[0]), (1.0, 9.0));
+        assert_eq!(start(&results[1]), (0.0, 4.0));
+        assert_eq!(start(&results[2]), (5.0, 2.0));
+        assert_eq!(start(&results[3]), (5.0, 2.0));
+        assert_eq!(start(&results[4]), (4.0, 6.0));
+        assert_eq!(results[0].get("uri").and_then(Json::as_str), Some(URI));
+    }
+
+    #[test]
+    fn finds_references() {
+        let results = results(&[
+            at(1.0, "textDocument/references", 0, 5),
+            at(2.0, "textDocument/references", 1, 10),
+            at(3.0, "textDocument/references", 5, 3),
+        ]);
+        // The global, but not the parameter that shadows it
+        assert_eq!(starts(&results[0]), [(0.0, 4.0), (10.0, 5.0)]);
+        assert_eq!(starts(&results[1]), [(1.0, 9.0), (2.0, 8.0)]);
+        assert_eq!(starts(&results[2]), [(5.0, 2.0), (6.0, 28.0), (6.0, 47.0), (9.0, 8.0)]);
+    }
+
+    #[test]
+    fn lists_document_symbols() {
+        let params = Json::object(vec![(
+            "textDocument",
+            Json::object(vec![("uri", Json::string(URI))]),
+        )]);
+        let results = results(&[message(Some(1.0), "textDocument/documentSymbol", params)]);
+        assert_eq!(fields(&results[0], "name"), ["count", "show", "Greeter", "g"]);
+        let class = &results[0].as_array().unwrap()[2];
+        assert_eq!(class.get("kind"), Some(&Json::Number(5.0)));
+        assert_eq!(fields(class.get("children").unwrap(), "name"), ["greet", "twice"]);
+    }
+
+    #[test]
+    fn completes_visible_names_and_methods() {
+        let results = results(&[
+            at(1.0, "textDocument/completion", 2, 8),
+            at(2.0, "textDocument/completion", 9, 8),
+        ]);
+        let names = fields(&results[0], "label");
+        // The parameter hides the global of the same name
+        assert_eq!(names.iter().filter(|name| **name == "count").count(), 1);
+        assert_eq!(fields(&results[0], "detail")[0], "parameter count");
+        assert!(names.contains(&"show") && names.contains(&"clock") && names.contains(&"while"));
+        assert!(!names.contains(&"greet"));
+        assert_eq!(fields(&results[1], "label"), ["greet", "twice"]);
+    }
+}
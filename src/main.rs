@@ -1,107 +1,643 @@
-mod parser;
-mod rox_type;
-mod scanner;
-mod token;
-mod token_type;
-mod interpreter;
-
 use std::env;
-use std::fmt::Error;
-use std::fs::read_to_string;
-use std::io;
+use std::fs::{self, read_to_string};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+mod debug_console;
+mod repl;
+mod test_runner;
+
+use debug_console::Console;
+
+use rox::dap;
+use rox::debugger::{Debugger, Session};
+use rox::scanner::Scanner;
+use rox::formatter::format_source;
+use rox::lsp::Server;
+use rox::vm::Vm;
+use rox::{ast_json, ast_printer, chunk, roxc};
+use rox::{Rox, RoxError};
+
+// sysexits.h exit codes
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+const USAGE: &str = "Usage: rox [run] [options] [script | -e <code> | -] [args...]
+       rox compile script [-o output]
+       rox fmt [--check] [path... | -]
+       rox lsp
+       rox debug [script [args...] | --dap]
+       rox test [--backend <name>] [--gc-stress] path...
+
+Runs a Lox script, or starts an interactive prompt when no script is given.
+Arguments after the script are passed to it through argc() and argv(n).
+
+Options:
+  -e <code>        Run <code> instead of a script file
+  -                Read the script from standard input
+  --check          Scan, parse and resolve only
+  --dump-tokens    Print the token stream and exit
+  --dump-ast       Print the syntax tree and exit
+  --dump-ast-json  Print the syntax tree as versioned JSON and exit
+  --disassemble    Print the compiled bytecode and exit
+  --backend <name> Run on the tree-walking interpreter (tree, the default)
+                   or compile to bytecode for the stack VM (vm)
+  --gc-threshold <n>
+                   Look for unreachable cycles after <n> allocations
+                   (default 10000), or as many as are live if that's more.
+                   Tree backend only, like --gc-stress
+  --gc-stress      Look for unreachable cycles on every allocation
+  -h, --help       Show this message
+
+rox compile compiles a script to bytecode and saves it, by default next to
+the script with a .roxc extension. rox run loads such files directly and
+runs them on the VM backend, skipping the compiler.
 
-use interpreter::Interpreter;
-use parser::Parser;
-use scanner::Scanner;
+rox fmt rewrites each .lox file in place, searching directories. With no
+path, or -, it formats standard input to standard output. --check only
+lists the files that would change and exits with 1 if there are any.
+
+rox lsp runs a language server on standard input and output.
+
+rox debug runs a script under an interactive debugger, stopped before its
+first statement; type help at the (rox) prompt for the commands. With
+--dap it is a Debug Adapter Protocol server on standard input and output
+instead, for editors.
+
+rox test runs each .lox file, searching directories, and checks what it
+prints against its // expect: comments and its errors against
+// expect runtime error: and // [line N] Error comments, as in the Crafting
+Interpreters test suite. Files marked // nontest are skipped. --backend
+and --gc-stress are passed on to each run.";
+
+#[derive(PartialEq)]
+enum Mode {
+    Run,
+    Check,
+    DumpTokens,
+    DumpAst,
+    DumpAstJson,
+    Disassemble,
+}
+
+#[derive(PartialEq)]
+enum Backend {
+    Tree,
+    Vm,
+}
+
+enum Source {
+    File(String),
+    Inline(String),
+    Stdin,
+    Prompt,
+}
 
+struct Options {
+    mode: Mode,
+    backend: Backend,
+    gc_threshold: Option<usize>,
+    gc_stress: bool,
+    source: Source,
+    script_args: Vec<String>,
+}
 
-#[macro_use]
-extern crate lazy_static;
+// The tree-walker recurses on the Rust stack for every Lox call, so
+// everything runs on a thread with room for its deepest call chain
+const STACK_SIZE: usize = 512 << 20;
 
 fn main() {
-    let mut interpreter = Interpreter::new();
-    let mut args = env::args();
-    if args.len() > 2 {
-        println!("Usage: jlox [script]")
-    } else if args.len() == 2 {
-        let path =  &args.nth(1).expect("failed to get path argument");
-        match run_file(path, &mut interpreter) {
-            Ok(()) => (),
-            Err(error) => panic!("Problem opening the file: {:?}", error),
+    let runner = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("couldn't start the interpreter thread");
+    if runner.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn run() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some("fmt") => process::exit(run_fmt(&args[1..])),
+        Some("compile") => process::exit(run_compile(&args[1..])),
+        Some("lsp") => process::exit(run_lsp()),
+        Some("debug") => process::exit(run_debug(&args[1..])),
+        Some("test") => process::exit(run_test(&args[1..])),
+        _ => (),
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(EX_USAGE);
+        }
+    };
+
+    let code = match &options.source {
+        Source::File(path) => match fs::read(path) {
+            Ok(bytes) if roxc::is_compiled(&bytes) => {
+                if uses_gc_options(&options) {
+                    eprintln!("{}; {} is compiled for the vm\n\n{}", GC_VM_ERROR, path, USAGE);
+                    process::exit(EX_USAGE);
+                }
+                if let Err(error) = run_compiled(options.mode, &bytes, options.script_args) {
+                    eprintln!("{}", error);
+                    process::exit(exit_code(&error));
+                }
+                return;
+            }
+            Ok(bytes) => String::from_utf8(bytes).map_err(|_| {
+                let error = io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+                (path.as_str(), error)
+            }),
+            Err(error) => Err((path.as_str(), error)),
+        },
+        Source::Inline(code) => Ok(code.clone()),
+        Source::Stdin => {
+            let mut code = String::new();
+            io::stdin()
+                .read_to_string(&mut code)
+                .map(|_| code)
+                .map_err(|e| ("<stdin>", e))
+        }
+        Source::Prompt => {
+            let mut rox = Rox::new();
+            configure_gc(&mut rox, &options);
+            rox.set_args(options.script_args);
+            if let Err(error) = repl::run_prompt(&mut rox) {
+                eprintln!("Prompt failed: {}", error);
+                process::exit(EX_IOERR);
+            }
+            return;
+        }
+    };
+    let code = match code {
+        Ok(code) => code,
+        Err((path, error)) => {
+            eprintln!("Could not read {}: {}", path, error);
+            process::exit(EX_IOERR);
+        }
+    };
+
+    let result = match options.mode {
+        Mode::Run if options.backend == Backend::Vm => {
+            Rox::compile_bytecode(&code).and_then(|program| {
+                let mut vm = Vm::new();
+                vm.set_args(options.script_args);
+                vm.run(&program).map_err(|e| RoxError::Runtime(e.locate(&code)))
+            })
         }
-    } else {
-        match run_prompt(&mut interpreter) {
-            Ok(()) => (),
-            Err(error) => panic!("Prompt failed: {:?}", error),
+        Mode::Run => {
+            let mut rox = Rox::new();
+            configure_gc(&mut rox, &options);
+            rox.set_args(options.script_args);
+            rox.eval(&code)
         }
+        Mode::Check if options.backend == Backend::Vm => Rox::compile_bytecode(&code).map(|_| ()),
+        Mode::Check => Rox::compile(&code).map(|_| ()),
+        Mode::DumpTokens => dump_tokens(&code),
+        Mode::DumpAst => Rox::compile(&code)
+            .map(|statements| println!("{}", ast_printer::print_program(&statements))),
+        Mode::DumpAstJson => Rox::compile(&code)
+            .map(|statements| println!("{}", ast_json::to_json(&statements))),
+        Mode::Disassemble => Rox::compile_bytecode(&code)
+            .map(|program| print!("{}", chunk::disassemble(&program))),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(exit_code(&error));
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        mode: Mode::Run,
+        backend: Backend::Tree,
+        gc_threshold: None,
+        gc_stress: false,
+        source: Source::Prompt,
+        script_args: Vec::new(),
+    };
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("run") {
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--check" => options.mode = Mode::Check,
+            "--dump-tokens" => options.mode = Mode::DumpTokens,
+            "--dump-ast" => options.mode = Mode::DumpAst,
+            "--dump-ast-json" => options.mode = Mode::DumpAstJson,
+            "--disassemble" => options.mode = Mode::Disassemble,
+            "--backend" => {
+                options.backend = match args.next().as_deref() {
+                    Some("tree") => Backend::Tree,
+                    Some("vm") => Backend::Vm,
+                    Some(other) => return Err(format!("Unknown backend '{}'", other)),
+                    None => return Err(String::from("--backend needs an argument")),
+                }
+            }
+            "--gc-threshold" => {
+                let threshold = args.next().ok_or("--gc-threshold needs an argument")?;
+                match threshold.parse::<usize>() {
+                    Ok(threshold) if threshold > 0 => options.gc_threshold = Some(threshold),
+                    _ => return Err(format!("Invalid --gc-threshold '{}'", threshold)),
+                }
+            }
+            "--gc-stress" => options.gc_stress = true,
+            "-e" => {
+                let code = args.next().ok_or("-e needs an argument")?;
+                options.source = Source::Inline(code);
+                break;
+            }
+            "-" => {
+                options.source = Source::Stdin;
+                break;
+            }
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ => {
+                options.source = Source::File(arg);
+                break;
+            }
+        }
+    }
+    // Everything after the script belongs to the script
+    options.script_args = args.collect();
+
+    if let (Source::Prompt, false) = (&options.source, options.mode == Mode::Run) {
+        return Err(String::from("Options other than run need a script"));
     }
-    if interpreter.had_runtime_error {
-        std::process::exit(70);
+    if let (Source::Prompt, Backend::Vm) = (&options.source, &options.backend) {
+        return Err(String::from("The vm backend needs a script"));
     }
+    if options.backend == Backend::Vm && uses_gc_options(&options) {
+        return Err(String::from(GC_VM_ERROR));
+    }
+    Ok(options)
 }
 
-// Run source file
-fn run_file(path: &str, interpreter: &mut Interpreter) -> Result<(), io::Error> {
-    let code = read_to_string(path)?;
+const GC_VM_ERROR: &str = "--gc-threshold and --gc-stress only apply to the tree backend";
 
-    match run(&code, interpreter) {
-        Ok(()) => return Ok(()),
-        Err(e) => panic!("{e}"),
-    };
+fn uses_gc_options(options: &Options) -> bool {
+    options.gc_threshold.is_some() || options.gc_stress
+}
+
+fn configure_gc(rox: &mut Rox, options: &Options) {
+    if let Some(threshold) = options.gc_threshold {
+        rox.interpreter().set_gc_threshold(threshold);
+    }
+    rox.interpreter().set_gc_stress(options.gc_stress);
+}
+
+// Compiled scripts always run on the VM
+fn run_compiled(mode: Mode, bytes: &[u8], script_args: Vec<String>) -> Result<(), RoxError> {
+    let program = roxc::decode(bytes).map_err(RoxError::Load)?;
+    match mode {
+        Mode::Run => {
+            let mut vm = Vm::new();
+            vm.set_args(script_args);
+            vm.run(&program).map_err(RoxError::Runtime)
+        }
+        Mode::Check => Ok(()),
+        Mode::Disassemble => {
+            print!("{}", chunk::disassemble(&program));
+            Ok(())
+        }
+        Mode::DumpTokens | Mode::DumpAst | Mode::DumpAstJson => {
+            eprintln!("A compiled script has no source to dump\n\n{}", USAGE);
+            process::exit(EX_USAGE);
+        }
+    }
 }
 
-// Interactve shell
-fn run_prompt(interpreter: &mut Interpreter) -> Result<(), io::Error> {
+fn dump_tokens(code: &str) -> Result<(), RoxError> {
+    let mut scanner = Scanner::new(code);
+    for token in scanner.scan_tokens() {
+        println!(
+            "{}:{} {:?} '{}' {}",
+            token.span.line, token.span.column, token.token_type, token.lexeme, token.literal
+        );
+    }
+    if !scanner.errors().is_empty() {
+        return Err(RoxError::Parse(scanner.errors().to_vec()));
+    }
+    Ok(())
+}
+
+fn run_lsp() -> i32 {
     let stdin = io::stdin();
-    let mut buf = String::new();
-
-    loop {
-        print!("> ");
-        stdin.read_line(&mut buf)?;
-        if buf == "c\n" {
-            break;
-        } else {
-            match run(&buf, interpreter) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("{:?}", e);
-                    return Ok(());
+    let stdout = io::stdout();
+    match Server::new().run(stdin.lock(), stdout.lock()) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Language server failed: {}", error);
+            EX_IOERR
+        }
+    }
+}
+
+// Returns the exit code
+fn run_compile(args: &[String]) -> i32 {
+    let mut path = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            "-o" => match args.next() {
+                Some(file) => output = Some(PathBuf::from(file)),
+                None => {
+                    eprintln!("-o needs an argument\n\n{}", USAGE);
+                    return EX_USAGE;
+                }
+            },
+            flag if flag.starts_with('-') => {
+                eprintln!("Unknown option '{}'\n\n{}", flag, USAGE);
+                return EX_USAGE;
+            }
+            _ if path.is_some() => {
+                eprintln!("rox compile takes one script\n\n{}", USAGE);
+                return EX_USAGE;
+            }
+            file => path = Some(PathBuf::from(file)),
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("rox compile needs a script\n\n{}", USAGE);
+        return EX_USAGE;
+    };
+    let output = output.unwrap_or_else(|| path.with_extension("roxc"));
+
+    let code = match read_to_string(&path) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path.display(), error);
+            return EX_IOERR;
+        }
+    };
+    let program = match Rox::compile_bytecode(&code) {
+        Ok(program) => program,
+        Err(error) => {
+            eprintln!("{}", error);
+            return exit_code(&error);
+        }
+    };
+    if let Err(error) = fs::write(&output, roxc::encode(&program)) {
+        eprintln!("Could not write {}: {}", output.display(), error);
+        return EX_IOERR;
+    }
+    0
+}
+
+// Returns the exit code
+fn run_test(args: &[String]) -> i32 {
+    let mut paths = Vec::new();
+    let mut run_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            "--gc-stress" => run_args.push(arg.clone()),
+            "--backend" => match args.next() {
+                Some(backend) => run_args.extend([arg.clone(), backend.clone()]),
+                None => {
+                    eprintln!("--backend needs an argument\n\n{}", USAGE);
+                    return EX_USAGE;
                 }
+            },
+            flag if flag.starts_with('-') => {
+                eprintln!("Unknown option '{}'\n\n{}", flag, USAGE);
+                return EX_USAGE;
             }
+            path => paths.push(PathBuf::from(path)),
         }
-        buf.drain(..);
     }
-    return Ok(());
+    if paths.is_empty() {
+        eprintln!("rox test needs a file or directory\n\n{}", USAGE);
+        return EX_USAGE;
+    }
+    let uses_vm = run_args.windows(2).any(|pair| pair[0] == "--backend" && pair[1] == "vm");
+    if uses_vm && run_args.iter().any(|arg| arg == "--gc-stress") {
+        eprintln!("{}\n\n{}", GC_VM_ERROR, USAGE);
+        return EX_USAGE;
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if let Err(error) = lox_files(&path, &mut files) {
+            eprintln!("Could not read {}: {}", path.display(), error);
+            return EX_IOERR;
+        }
+    }
+    test_runner::run_tests(&files, &run_args)
 }
 
-// Run Interpereter
-fn run(code: &String, interpreter: &mut Interpreter) -> Result<(), Error> {
+// Returns the exit code
+fn run_debug(args: &[String]) -> i32 {
+    let path = match args.first().map(String::as_str) {
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        Some("--dap") => {
+            return match dap::serve(BufReader::new(io::stdin()), io::stdout()) {
+                Ok(code) => code,
+                Err(error) => {
+                    eprintln!("Debug adapter failed: {}", error);
+                    EX_IOERR
+                }
+            };
+        }
+        Some(path) => path,
+        None => {
+            eprintln!("rox debug needs a script\n\n{}", USAGE);
+            return EX_USAGE;
+        }
+    };
+    let code = match read_to_string(path) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    let console = match Console::new(&code) {
+        Ok(console) => console,
+        Err(error) => {
+            eprintln!("Prompt failed: {}", error);
+            return EX_IOERR;
+        }
+    };
 
-    let mut scanner = Scanner::new(code);
-    let tokens = scanner.scan_tokens();
+    let session = Session::default();
+    pause_on_interrupt(session.pause.clone());
+    let mut rox = Rox::new();
+    rox.set_args(args[1..].to_vec());
+    rox.interpreter()
+        .set_debugger(Box::new(Debugger::new(console, session, true)));
+    match rox.eval(&code) {
+        Ok(()) => {
+            println!("Program finished");
+            0
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            exit_code(&error)
+        }
+    }
+}
+
+// Makes Ctrl-C stop a running program in the debugger rather than end it
+#[cfg(unix)]
+fn pause_on_interrupt(pause: Arc<AtomicBool>) {
+    static PAUSE: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        if let Some(pause) = PAUSE.get() {
+            pause.store(true, Ordering::SeqCst);
+        }
+    }
+
+    if PAUSE.set(pause).is_ok() {
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn pause_on_interrupt(_pause: Arc<AtomicBool>) {}
 
-    let mut parser = Parser::new(tokens);
-    let result = parser.parse();
+// Returns the exit code
+fn run_fmt(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            "--check" => check = true,
+            "-" => paths.push(PathBuf::from("-")),
+            flag if flag.starts_with('-') => {
+                eprintln!("Unknown option '{}'\n\n{}", flag, USAGE);
+                return EX_USAGE;
+            }
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+    if paths.is_empty() {
+        paths.push(PathBuf::from("-"));
+    }
+
+    let mut status = 0;
+    for path in paths {
+        if path == Path::new("-") {
+            let mut code = String::new();
+            if let Err(error) = io::stdin().read_to_string(&mut code) {
+                eprintln!("Could not read <stdin>: {}", error);
+                return EX_IOERR;
+            }
+            match format_source(&code) {
+                Ok(formatted) if check && formatted != code => {
+                    println!("Would reformat <stdin>");
+                    status = status.max(1);
+                }
+                Ok(formatted) if !check => print!("{}", formatted),
+                Ok(_) => (),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    status = status.max(exit_code(&error));
+                }
+            }
+            continue;
+        }
 
-    if let Err(parse_err) = result {
-        println!("Parse Error: {:?}", parse_err);
-        return Ok(())
+        let mut files = Vec::new();
+        if let Err(error) = lox_files(&path, &mut files) {
+            eprintln!("Could not read {}: {}", path.display(), error);
+            status = status.max(EX_IOERR);
+        }
+        for file in files {
+            status = status.max(fmt_file(&file, check));
+        }
     }
-    let result = result.unwrap();
+    status
+}
 
-    let result = interpreter.interpret(result);
-    if let Err(ie) = result {
-        println!("Interpreter error: {:?}", ie);
+fn fmt_file(path: &Path, check: bool) -> i32 {
+    let code = match read_to_string(path) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path.display(), error);
+            return EX_IOERR;
+        }
+    };
+    let formatted = match format_source(&code) {
+        Ok(formatted) => formatted,
+        Err(error) => {
+            eprintln!("Could not format {}:\n{}", path.display(), error);
+            return exit_code(&error);
+        }
+    };
+    if formatted == code {
+        return 0;
+    }
+    if check {
+        println!("Would reformat {}", path.display());
+        return 1;
+    }
+    if let Err(error) = fs::write(path, formatted) {
+        eprintln!("Could not write {}: {}", path.display(), error);
+        return EX_IOERR;
     }
-    
-    return Ok(());
+    0
 }
 
-// Token enum for scanning
-fn error(line: u32, message: &str) {
-    report(line, "", message)
+// The path itself if it's a file, otherwise every .lox file beneath it
+fn lox_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            lox_files(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "lox") {
+            files.push(entry);
+        }
+    }
+    Ok(())
 }
 
-fn report(line: u32, location: &str, message: &str) {
-    eprintln!("[line  {}] Error{}: {}", line, location, message)
+fn exit_code(error: &RoxError) -> i32 {
+    match error {
+        RoxError::Io(_) => EX_IOERR,
+        RoxError::Parse(_) | RoxError::Resolve(_) | RoxError::Load(_) => EX_DATAERR,
+        RoxError::Runtime(_) => EX_SOFTWARE,
+    }
 }
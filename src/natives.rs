@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::callable::NativeFunction;
+use crate::interpreter::{Interpreter, InterpreterError, InterpreterResult};
+use crate::rox_type::RoxType;
+
+// Built-in functions available to every script
+pub fn define_builtins(interpreter: &mut Interpreter) {
+    for native in builtins() {
+        interpreter.define_native_function(native);
+    }
+}
+
+pub(crate) fn builtins() -> Vec<NativeFunction> {
+    vec![NativeFunction::new("clock", 0, Box::new(clock))]
+}
+
+// Seconds since the Unix epoch
+fn clock(_arguments: &[RoxType]) -> InterpreterResult {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(RoxType::Number(elapsed.as_secs_f64())),
+        Err(e) => Err(InterpreterError::new(&format!("clock() failed: {}", e))),
+    }
+}
+
+pub fn define_args(interpreter: &mut Interpreter, args: Vec<String>) {
+    for native in arg_natives(args) {
+        interpreter.define_native_function(native);
+    }
+}
+
+// argc() is the argument count and argv(n) the nth argument, or nil
+pub(crate) fn arg_natives(args: Vec<String>) -> Vec<NativeFunction> {
+    let argc = args.len() as f64;
+    let argv = move |arguments: &[RoxType]| match arguments[0] {
+        RoxType::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(args
+            .get(n as usize)
+            .map_or(RoxType::Nil, |arg| RoxType::String(arg.clone()))),
+        _ => Err(InterpreterError::new("argv() expects a non-negative integer.")),
+    };
+    vec![
+        NativeFunction::new("argc", 0, Box::new(move |_| Ok(RoxType::Number(argc)))),
+        NativeFunction::new("argv", 1, Box::new(argv)),
+    ]
+}
@@ -1,12 +1,14 @@
 use crate::rox_type::RoxType;
-use crate::token::Token;
+use crate::token::{Span, Token};
 use crate::token_type::TokenType::{self, *};
 
+use std::rc::Rc;
 use std::string::String;
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    errors: Vec<ParseErr>,
 }
 
 #[derive(Debug)]
@@ -17,18 +19,42 @@ pub struct ParseErr {
 
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Assign(Token, Box<Expr>),
+    // The trailing depth is the number of scopes between the use and its
+    // declaration, filled in by the resolver; None means global
+    Assign(Token, Box<Expr>, Option<usize>),
     Binary(Box<Expr>, Token, Box<Expr>),
+    Call(Box<Expr>, Token, Vec<Expr>),
+    Get(Box<Expr>, Token),
     Grouping(Box<Expr>),
     Literal(RoxType),
+    Logical(Box<Expr>, Token, Box<Expr>),
+    Set(Box<Expr>, Token, Box<Expr>),
+    // object.name op= value, with the object evaluated once; the middle
+    // token is the binary operator
+    CompoundSet(Box<Expr>, Token, Token, Box<Expr>),
+    // target++ or target-- on a variable or property, yielding the old
+    // value; the token is the binary operator that steps it
+    Postfix(Box<Expr>, Token),
+    Super(Token, Token, Option<usize>),
+    This(Token, Option<usize>),
     Unary(Token, Box<Expr>),
-    Variable(Token)
+    Variable(Token, Option<usize>)
 }
 
+#[derive(Debug, Clone)]
 pub enum Stmt {
+    Block(Vec<Stmt>),
+    // The superclass is an Expr::Variable; methods are Stmt::Function
+    Class(Token, Option<Box<Expr>>, Vec<Stmt>),
     Expression(Box<Expr>),
-    Print(Box<Expr>),
-    Var(Token, Option<Box<Expr>>)
+    // Parameters and body are shared with every closure made from it
+    Function(Token, Rc<[Token]>, Rc<[Stmt]>),
+    // If, Print and While keep their keyword to know which line they're on
+    If(Token, Box<Expr>, Box<Stmt>, Option<Box<Stmt>>),
+    Print(Token, Box<Expr>),
+    Return(Token, Option<Box<Expr>>),
+    Var(Token, Option<Box<Expr>>),
+    While(Token, Box<Expr>, Box<Stmt>),
 }
 
 
@@ -40,29 +66,96 @@ impl Parser {
         Parser {
             tokens: list,
             current: 0,
+            errors: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, ParseErr> {
+    // Parses the whole program, recovering after each error so every one
+    // is reported together
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<ParseErr>> {
+        let (statements, errors) = self.parse_partial();
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Like parse, but keeps the statements that did parse alongside the
+    // errors, for tools that work on code while it's being edited
+    pub fn parse_partial(&mut self) -> (Vec<Stmt>, Vec<ParseErr>) {
         let mut statements = Vec::new();
         while !self.at_end() {
-            statements.push(self.declaration()?)
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
         }
-        Ok(statements)
+        (statements, std::mem::take(&mut self.errors))
     }
 
-    fn declaration(&mut self) -> Result<Stmt, ParseErr> {
-        let result;
-        if self.match_tokens(&[Var]) {
-            result = self.var_declaration();
+    // Records a failed declaration and skips to the next statement boundary
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = if self.match_tokens(&[Class]) {
+            self.class_declaration()
+        } else if self.match_tokens(&[Fun]) {
+            self.function("function")
+        } else if self.match_tokens(&[Var]) {
+            self.var_declaration()
         } else {
-            result = self.statement();
+            self.statement()
+        };
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.errors.push(e);
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, ParseErr> {
+        let name = self.consume(Identifier, "Expect class name.")?.clone();
+
+        let mut superclass = None;
+        if self.match_tokens(&[Less]) {
+            let superclass_name = self.consume(Identifier, "Expect superclass name.")?.clone();
+            superclass = Some(Expr::Variable(superclass_name, None).into());
+        }
+
+        self.consume(LeftBrace, "Expect '{' before class body.")?;
+
+        let mut methods = Vec::new();
+        while !self.check(RightBrace) && !self.at_end() {
+            methods.push(self.function("method")?);
         }
-        if let Err(e) = result {
-            self.synchronize();
-            return Err(e)
+        self.consume(RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class(name, superclass, methods))
+    }
+
+    fn function(&mut self, kind: &str) -> Result<Stmt, ParseErr> {
+        let name = self.consume(Identifier, &format!("Expect {kind} name."))?.clone();
+        self.consume(LeftParen, &format!("Expect '(' after {kind} name."))?;
+        let mut params = Vec::new();
+        if !self.check(RightParen) {
+            loop {
+                if params.len() >= 255 {
+                    return Err(ParseErr {
+                        err_token: self.peek().clone(),
+                        err_msg: String::from("Can't have more than 255 parameters."),
+                    });
+                }
+                params.push(self.consume(Identifier, "Expect parameter name.")?.clone());
+                if !self.match_tokens(&[Comma]) {
+                    break;
+                }
+            }
         }
-        result
+        self.consume(RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(LeftBrace, &format!("Expect '{{' before {kind} body."))?;
+        let body = self.block()?;
+        Ok(Stmt::Function(name, params.into(), body.into()))
     }
 
     fn var_declaration(&mut self) -> Result<Stmt, ParseErr> {
@@ -71,20 +164,120 @@ impl Parser {
         if self.match_tokens(&[Equal]) {
             initalizer = Some(self.expression()?.into());
         }
+        self.consume(Semicolon, "Expect ';' after variable declaration.")?;
         Ok(Stmt::Var(name, initalizer))
     }
 
     fn statement(&mut self) -> Result<Stmt, ParseErr>{
+        if self.match_tokens(&[For]) {
+            return self.for_statement()
+        }
+        if self.match_tokens(&[If]) {
+            return self.if_statement()
+        }
         if self.match_tokens(&[Print]) {
             return self.print_statement()
         }
+        if self.match_tokens(&[Return]) {
+            return self.return_statement()
+        }
+        if self.match_tokens(&[While]) {
+            return self.while_statement()
+        }
+        if self.match_tokens(&[LeftBrace]) {
+            return Ok(Stmt::Block(self.block()?))
+        }
         self.expression_statement()
     }
 
+    fn block(&mut self) -> Result<Vec<Stmt>, ParseErr> {
+        let mut statements = Vec::new();
+        while !self.check(RightBrace) && !self.at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+        self.consume(RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    // for loops are desugared into a while loop wrapped in blocks
+    fn for_statement(&mut self) -> Result<Stmt, ParseErr> {
+        let keyword = self.previous().clone();
+        self.consume(LeftParen, "Expect '(' after 'for'.")?;
+
+        let initializer = if self.match_tokens(&[Semicolon]) {
+            None
+        } else if self.match_tokens(&[Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let condition = if self.check(Semicolon) {
+            Expr::Literal(RoxType::Boolean(true))
+        } else {
+            self.expression()?
+        };
+        self.consume(Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(RightParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.statement()?;
+
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment.into())]);
+        }
+        body = Stmt::While(keyword, condition.into(), body.into());
+        if let Some(initializer) = initializer {
+            body = Stmt::Block(vec![initializer, body]);
+        }
+        Ok(body)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ParseErr> {
+        let keyword = self.previous().clone();
+        self.consume(LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(RightParen, "Expect ')' after if condition.")?;
+
+        let then_branch = self.statement()?;
+        let mut else_branch = None;
+        if self.match_tokens(&[Else]) {
+            else_branch = Some(self.statement()?.into());
+        }
+        Ok(Stmt::If(keyword, condition.into(), then_branch.into(), else_branch))
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, ParseErr> {
+        let keyword = self.previous().clone();
+        self.consume(LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(RightParen, "Expect ')' after condition.")?;
+        let body = self.statement()?;
+        Ok(Stmt::While(keyword, condition.into(), body.into()))
+    }
+
     fn print_statement(&mut self) -> Result<Stmt, ParseErr> {
+        let keyword = self.previous().clone();
         let val = self.expression()?;
         self.consume(Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Print(val.into()))
+        Ok(Stmt::Print(keyword, val.into()))
+    }
+
+    fn return_statement(&mut self) -> Result<Stmt, ParseErr> {
+        let keyword = self.previous().clone();
+        let mut value = None;
+        if !self.check(Semicolon) {
+            value = Some(self.expression()?.into());
+        }
+        self.consume(Semicolon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return(keyword, value))
     }
 
     fn expression_statement(&mut self) -> Result<Stmt, ParseErr> {
@@ -94,11 +287,48 @@ impl Parser {
     }
 
     fn expression(&mut self) -> ParseExprResult {
-        self.equality()
+        self.assign()
     }
 
     fn assign(&mut self) -> ParseExprResult {
-        
+        let expr = self.or()?;
+
+        if self.match_tokens(&[Equal, PlusEqual, MinusEqual, StarEqual, SlashEqual]) {
+            let equals = self.previous().clone();
+            let value = self.assign()?;
+            let operator = match equals.token_type {
+                PlusEqual => Plus,
+                MinusEqual => Minus,
+                StarEqual => Star,
+                SlashEqual => Slash,
+                _ => return assignment_to(expr, value, equals),
+            };
+            let operator = operator_token(&equals, operator);
+            return compound_assignment(expr, operator, value, equals);
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> ParseExprResult {
+        let mut expr = self.and()?;
+
+        while self.match_tokens(&[Or]) {
+            let operator = self.previous().clone();
+            let right = self.and()?;
+            expr = Expr::Logical(expr.into(), operator, right.into());
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseExprResult {
+        let mut expr = self.equality()?;
+
+        while self.match_tokens(&[And]) {
+            let operator = self.previous().clone();
+            let right = self.equality()?;
+            expr = Expr::Logical(expr.into(), operator, right.into());
+        }
+        Ok(expr)
     }
 
     fn equality(&mut self) -> ParseExprResult {
@@ -148,15 +378,72 @@ impl Parser {
             let right = self.unary()?;
             return Ok(Expr::Unary(operator, right.into()));
         }
-        return self.primary();
+        // ++a is sugar for a = a + 1
+        if self.match_tokens(&[PlusPlus, MinusMinus]) {
+            let operator = self.previous().clone();
+            let target = self.unary()?;
+            return increment(target, &operator);
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> ParseExprResult {
+        let expr = self.call()?;
+        if self.match_tokens(&[PlusPlus, MinusMinus]) {
+            let operator = self.previous().clone();
+            if !matches!(expr, Expr::Variable(..) | Expr::Get(..)) {
+                return Err(ParseErr {
+                    err_token: operator,
+                    err_msg: String::from("Invalid assignment target."),
+                });
+            }
+            return Ok(Expr::Postfix(expr.into(), step_token(&operator)));
+        }
+        Ok(expr)
+    }
+
+    fn call(&mut self) -> ParseExprResult {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.match_tokens(&[LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_tokens(&[Dot]) {
+                let name = self.consume(Identifier, "Expect property name after '.'.")?.clone();
+                expr = Expr::Get(expr.into(), name);
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseExprResult {
+        let mut arguments = Vec::new();
+        if !self.check(RightParen) {
+            loop {
+                if arguments.len() >= 255 {
+                    return Err(ParseErr {
+                        err_token: self.peek().clone(),
+                        err_msg: String::from("Can't have more than 255 arguments."),
+                    });
+                }
+                arguments.push(self.expression()?);
+                if !self.match_tokens(&[Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self.consume(RightParen, "Expect ')' after arguments.")?.clone();
+        Ok(Expr::Call(callee.into(), paren, arguments))
     }
 
     fn primary(&mut self) -> ParseExprResult {
         if self.match_tokens(&[False]) {
-            return Ok(Expr::Literal(RoxType::Boolean(true)))
+            return Ok(Expr::Literal(RoxType::Boolean(false)))
         } 
         if self.match_tokens(&[True]) {
-            return Ok(Expr::Literal(RoxType::Boolean(false)))
+            return Ok(Expr::Literal(RoxType::Boolean(true)))
         } 
         if self.match_tokens(&[Nil]) {
             return Ok(Expr::Literal(RoxType::Nil))
@@ -164,8 +451,17 @@ impl Parser {
         if self.match_tokens(&[Number, String]) {
             return Ok(Expr::Literal(self.previous().literal.clone()))
         } 
+        if self.match_tokens(&[Super]) {
+            let keyword = self.previous().clone();
+            self.consume(Dot, "Expect '.' after 'super'.")?;
+            let method = self.consume(Identifier, "Expect superclass method name.")?.clone();
+            return Ok(Expr::Super(keyword, method, None));
+        }
+        if self.match_tokens(&[This]) {
+            return Ok(Expr::This(self.previous().clone(), None));
+        }
         if self.match_tokens(&[Identifier]) {
-            return Ok(Expr::Variable(self.previous().clone()));
+            return Ok(Expr::Variable(self.previous().clone(), None));
         }
         if self.match_tokens(&[LeftParen]) {
             let expr = self.expression()?;
@@ -176,9 +472,9 @@ impl Parser {
             }
         } 
 
-        return Err(ParseErr {
+        Err(ParseErr {
             err_token: self.peek().clone(),
-            err_msg: String::from("Expect Expression"),
+            err_msg: String::from("Expect expression."),
         })
         
     }
@@ -190,8 +486,9 @@ impl Parser {
             if self.previous().token_type == Semicolon {
                 return;
             }
-            if let Return = self.peek().token_type {
-                return
+            match self.peek().token_type {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => (),
             }
 
             self.advance();
@@ -215,14 +512,14 @@ impl Parser {
                 return true;
             }
         }
-        return false;
+        false
     }
 
     fn check(&mut self, token_type: TokenType) -> bool {
         if self.at_end() {
-            return false;
+            false
         } else {
-            return self.peek().token_type == token_type;
+            self.peek().token_type == token_type
         }
     }
 
@@ -242,6 +539,69 @@ impl Parser {
     }
 
     fn previous(&mut self) -> &Token {
-        return self.tokens.get(self.current - 1).unwrap();
+        self.tokens.get(self.current - 1).unwrap()
+    }
+}
+
+// Builds the assignment of value to target, which must be a variable or property
+fn assignment_to(target: Expr, value: Expr, equals: Token) -> ParseExprResult {
+    match target {
+        Expr::Variable(name, _) => Ok(Expr::Assign(name, value.into(), None)),
+        Expr::Get(object, name) => Ok(Expr::Set(object, name, value.into())),
+        _ => Err(ParseErr {
+            err_token: equals,
+            err_msg: String::from("Invalid assignment target."),
+        }),
     }
 }
+
+// a op= b on a variable is sugar for a = a op b. A property gets a node of
+// its own, since its object may have side effects and must run only once.
+fn compound_assignment(
+    target: Expr,
+    operator: Token,
+    value: Expr,
+    equals: Token,
+) -> ParseExprResult {
+    match target {
+        Expr::Variable(..) => {
+            let value = Expr::Binary(target.clone().into(), operator, value.into());
+            assignment_to(target, value, equals)
+        }
+        Expr::Get(object, name) => Ok(Expr::CompoundSet(object, name, operator, value.into())),
+        _ => Err(ParseErr {
+            err_token: equals,
+            err_msg: String::from("Invalid assignment target."),
+        }),
+    }
+}
+
+// Desugars ++/-- on target into a compound assignment that yields the new value
+fn increment(target: Expr, operator: &Token) -> ParseExprResult {
+    let one = Expr::Literal(RoxType::Number(1.0));
+    compound_assignment(target, step_token(operator), one, operator.clone())
+}
+
+// The binary operator that ++ or -- applies
+fn step_token(operator: &Token) -> Token {
+    match operator.token_type {
+        PlusPlus => operator_token(operator, Plus),
+        _ => operator_token(operator, Minus),
+    }
+}
+
+// A synthesized binary operator token over the first character of the
+// compound operator, so errors underline just the '+' of '+=' they name
+fn operator_token(at: &Token, token_type: TokenType) -> Token {
+    let lexeme = match token_type {
+        Plus => "+",
+        Minus => "-",
+        Star => "*",
+        _ => "/",
+    };
+    let span = Span {
+        end: at.span.start + 1,
+        ..at.span
+    };
+    Token::new(token_type, lexeme, RoxType::Nil, span)
+}
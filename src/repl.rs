@@ -0,0 +1,122 @@
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use rox::scanner::Scanner;
+use rox::token_type::TokenType;
+use rox::{Rox, RoxType};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ". ";
+
+// Interactive shell. Input is collected until brackets and strings are
+// closed, bare expressions print their value, and errors don't end the
+// session. Ctrl-C drops the current input, Ctrl-D exits.
+pub fn run_prompt(rox: &mut Rox) -> Result<(), ReadlineError> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means a first session
+        let _ = editor.load_history(path);
+    }
+
+    let mut buf = String::new();
+    loop {
+        let prompt = if buf.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                buf.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        buf.push_str(&line);
+        buf.push('\n');
+        if is_incomplete(&buf) {
+            continue;
+        }
+
+        let code = std::mem::take(&mut buf);
+        if code.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(code.trim_end())?;
+        match rox.eval_line(&code) {
+            Ok(Some(RoxType::Nil)) | Ok(None) => (),
+            Ok(Some(value)) => println!("{}", value),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+// Whether the input so far leaves a bracket or string open
+fn is_incomplete(code: &str) -> bool {
+    let mut scanner = Scanner::new(code);
+    let mut depth = 0;
+    for token in scanner.scan_tokens() {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            _ => (),
+        }
+    }
+    let open_string = scanner
+        .errors()
+        .iter()
+        .any(|e| e.message == "Unterminated string.");
+    depth > 0 || open_string
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rox_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_unbalanced_brackets() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(is_incomplete("fun f() {\n  if (true) {\n    print 1;\n  }\n"));
+        assert!(is_incomplete("print (1 +\n"));
+        assert!(is_incomplete("print max(1,\n  min(2, 3)\n"));
+        assert!(!is_incomplete("fun f() {\n  print 1;\n}\n"));
+        assert!(!is_incomplete("print (1 + 2) * 3;\n"));
+    }
+
+    #[test]
+    fn extra_closing_brackets_are_left_to_the_parser() {
+        assert!(!is_incomplete("}\n"));
+        assert!(!is_incomplete("print 1);\n"));
+    }
+
+    #[test]
+    fn waits_for_unterminated_strings() {
+        assert!(is_incomplete("print \"hello\n"));
+        assert!(!is_incomplete("print \"hello\nworld\";\n"));
+        // Brackets inside strings and comments don't count
+        assert!(!is_incomplete("print \"{ (\";\n"));
+        assert!(!is_incomplete("print 1; // {\n"));
+    }
+
+    #[test]
+    fn expressions_and_statements_are_complete_lines() {
+        assert!(!is_incomplete("1 + 2\n"));
+        assert!(!is_incomplete("var a = 1;\n"));
+        assert!(!is_incomplete("\n"));
+    }
+}
@@ -0,0 +1,283 @@
+use std::rc::Rc;
+
+use crate::parser::{Expr, Stmt};
+use crate::symbol::{Symbol, SymbolMap};
+use crate::token::Token;
+
+// Static pass run between parsing and interpreting. Works out how many
+// scopes separate each local variable use from its declaration and reports
+// errors that can be caught before the program runs.
+pub struct Resolver {
+    // Each scope maps a name to whether its initializer has finished
+    scopes: Vec<SymbolMap<bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+    errors: Vec<ResolveErr>,
+}
+
+#[derive(Debug)]
+pub struct ResolveErr {
+    pub err_token: Token,
+    pub err_msg: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+impl Default for Resolver {
+    fn default() -> Resolver {
+        Resolver::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver {
+            scopes: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn resolve(mut self, statements: &mut [Stmt]) -> Result<(), Vec<ResolveErr>> {
+        self.resolve_stmts(statements);
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn resolve_stmts(&mut self, statements: &mut [Stmt]) {
+        for stmt in statements {
+            self.resolve_stmt(stmt);
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                self.resolve_stmts(statements);
+                self.end_scope();
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+
+                self.declare(name);
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    if let Expr::Variable(superclass_name, _) = superclass.as_ref() {
+                        if superclass_name.lexeme == name.lexeme {
+                            self.error(superclass_name, "A class can't inherit from itself.");
+                        }
+                    }
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass);
+
+                    self.begin_scope();
+                    if let Some(scope) = self.scopes.last_mut() {
+                        scope.insert(Symbol::SUPER, true);
+                    }
+                }
+
+                self.begin_scope();
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(Symbol::THIS, true);
+                }
+                for method in methods {
+                    if let Stmt::Function(method_name, params, body) = method {
+                        let function_type = if method_name.lexeme == Symbol::INIT {
+                            FunctionType::Initializer
+                        } else {
+                            FunctionType::Method
+                        };
+                        self.resolve_function(params, function_body(body), function_type);
+                    }
+                }
+                self.end_scope();
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+
+                self.current_class = enclosing_class;
+            }
+            Stmt::Expression(exp) => self.resolve_expr(exp),
+            Stmt::Function(name, params, body) => {
+                // Declared before the body so the function can recurse
+                self.declare(name);
+                self.define(name);
+                self.resolve_function(params, function_body(body), FunctionType::Function);
+            }
+            Stmt::If(_, condition, then_branch, else_branch) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch);
+                }
+            }
+            Stmt::Print(_, exp) => self.resolve_expr(exp),
+            Stmt::Return(keyword, exp) => {
+                if self.current_function == FunctionType::None {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+                if let Some(exp) = exp {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.resolve_expr(exp);
+                }
+            }
+            Stmt::Var(name, initializer) => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.resolve_expr(initializer);
+                }
+                self.define(name);
+            }
+            Stmt::While(_, condition, body) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(body);
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Assign(name, value, depth) => {
+                self.resolve_expr(value);
+                *depth = self.resolve_local(name);
+            }
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Call(callee, _, arguments) => {
+                self.resolve_expr(callee);
+                for argument in arguments {
+                    self.resolve_expr(argument);
+                }
+            }
+            Expr::Get(object, _) => self.resolve_expr(object),
+            Expr::Grouping(exp) | Expr::Postfix(exp, _) => self.resolve_expr(exp),
+            Expr::Literal(_) => (),
+            Expr::Set(object, _, value) | Expr::CompoundSet(object, _, _, value) => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            Expr::Super(keyword, _, depth) => {
+                match self.current_class {
+                    ClassType::None => {
+                        self.error(keyword, "Can't use 'super' outside of a class.")
+                    }
+                    ClassType::Class => {
+                        self.error(keyword, "Can't use 'super' in a class with no superclass.")
+                    }
+                    ClassType::Subclass => (),
+                }
+                *depth = self.resolve_local(keyword);
+            }
+            Expr::This(keyword, depth) => {
+                if self.current_class == ClassType::None {
+                    self.error(keyword, "Can't use 'this' outside of a class.");
+                    return;
+                }
+                *depth = self.resolve_local(keyword);
+            }
+            Expr::Unary(_, right) => self.resolve_expr(right),
+            Expr::Variable(name, depth) => {
+                let declared_not_defined = self
+                    .scopes
+                    .last()
+                    .and_then(|scope| scope.get(&name.lexeme.symbol()))
+                    == Some(&false);
+                if declared_not_defined {
+                    self.error(name, "Can't read local variable in its own initializer.");
+                }
+                *depth = self.resolve_local(name);
+            }
+        }
+    }
+
+    fn resolve_function(
+        &mut self,
+        params: &[Token],
+        body: &mut [Stmt],
+        function_type: FunctionType,
+    ) {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+
+        self.begin_scope();
+        for param in params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.resolve_stmts(body);
+        self.end_scope();
+
+        self.current_function = enclosing_function;
+    }
+
+    // Distance from the innermost scope to the one declaring the name, or
+    // None if it isn't found and is assumed to be global
+    fn resolve_local(&self, name: &Token) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(&name.lexeme.symbol()))
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(SymbolMap::default());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+        if scope.contains_key(&name.lexeme.symbol()) {
+            self.error(name, "Already a variable with this name in this scope.");
+            return;
+        }
+        scope.insert(name.lexeme.symbol(), false);
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme.symbol(), true);
+        }
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.errors.push(ResolveErr {
+            err_token: token.clone(),
+            err_msg: String::from(message),
+        });
+    }
+}
+
+// Bodies are usually only shared once the interpreter makes closures from
+// them; a caller that cloned the tree first gets its own copy resolved
+fn function_body(body: &mut Rc<[Stmt]>) -> &mut [Stmt] {
+    Rc::make_mut(body)
+}
@@ -1,18 +1,31 @@
-#[derive(Debug, Clone, PartialEq)]
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::callable::Callable;
+use crate::class::{RoxClass, RoxInstance};
+
+#[derive(Debug, Clone)]
 pub enum RoxType {
     String(String),
     Number(f64),
     Nil,
     Boolean(bool),
+    Callable(Rc<dyn Callable>),
+    Class(Rc<RoxClass>),
+    Instance(Rc<RefCell<RoxInstance>>),
 }
 
-impl RoxType {
-    pub fn to_string(&self) -> String{
-        match self{
-            RoxType::String(x) => x.clone(),
-            RoxType::Number(x) => x.to_string(),
-            RoxType::Nil => String::from("Nil"),
-            RoxType::Boolean(x) => x.to_string(),
+impl fmt::Display for RoxType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoxType::String(x) => write!(f, "{}", x),
+            RoxType::Number(x) => write!(f, "{}", x),
+            RoxType::Nil => write!(f, "nil"),
+            RoxType::Boolean(x) => write!(f, "{}", x),
+            RoxType::Callable(x) => write!(f, "{}", x),
+            RoxType::Class(x) => write!(f, "{}", x),
+            RoxType::Instance(x) => write!(f, "{}", x.borrow()),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Constant, Function, OpCode, Program};
+use crate::rox_type::RoxType;
+use crate::token::{Span, Token};
+use crate::token_type::TokenType;
+
+// Bumped whenever the encoding or the instruction set changes
+pub const ROXC_VERSION: u16 = 4;
+const MAGIC: &[u8; 4] = b"ROXC";
+const HEADER_LEN: usize = 16;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+// A compiled program as written by rox compile. All integers are little
+// endian.
+//
+//   header:    "ROXC", u16 version, u16 reserved (0), u32 payload length,
+//              u32 CRC-32 of the payload
+//   payload:   strings, globals, functions
+//   strings:   u32 count, then u32 length and UTF-8 bytes for each
+//   globals:   u32 count, then a u32 string index for each
+//   functions: u32 count, then for each: u32 name string, u8 arity,
+//              u8 upvalue count, u32 code length and the code, u32 token
+//              count and a token for each run of code compiled for one,
+//              u32 constant count and the constants
+//   token:     u32 offset where its code starts, u32 strings for the token
+//              type's name and the lexeme, then u32 start, end, line and
+//              column of its span
+//   constant:  a tag byte, then an f64 for a number, a u32 string index
+//              for a string, or a u32 index of an earlier function
+//
+// Nested functions come before the functions that contain them, so the
+// script is the last one.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    let globals = program
+        .globals
+        .iter()
+        .map(|name| encoder.string(name))
+        .collect::<Vec<u32>>();
+    encoder.function(&program.script);
+
+    let mut payload = Vec::new();
+    put_u32(&mut payload, encoder.strings.len() as u32);
+    for string in &encoder.strings {
+        put_u32(&mut payload, string.len() as u32);
+        payload.extend_from_slice(string.as_bytes());
+    }
+    put_u32(&mut payload, globals.len() as u32);
+    for global in globals {
+        put_u32(&mut payload, global);
+    }
+    put_u32(&mut payload, encoder.function_count);
+    payload.extend_from_slice(&encoder.functions);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&ROXC_VERSION.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    put_u32(&mut out, payload.len() as u32);
+    put_u32(&mut out, crc32(&payload));
+    out.extend_from_slice(&payload);
+    out
+}
+
+// Whether the bytes look like a compiled program rather than source
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Decodes a program produced by encode, checking it is intact and that the
+// bytecode only refers to constants, globals and code that exist
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    if !is_compiled(bytes) {
+        return Err(String::from("not a compiled rox script"));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(String::from("truncated header"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != ROXC_VERSION {
+        return Err(format!(
+            "unsupported format version {} (expected {}); compile the script again",
+            version, ROXC_VERSION
+        ));
+    }
+    let length = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+    let checksum = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() < length {
+        return Err(format!(
+            "truncated: expected {} bytes of program, found {}",
+            length,
+            payload.len()
+        ));
+    }
+    if payload.len() > length {
+        return Err(format!("{} unexpected bytes after the program", payload.len() - length));
+    }
+    if crc32(payload) != checksum {
+        return Err(String::from("checksum mismatch, the file is corrupt"));
+    }
+
+    let mut decoder = Decoder {
+        bytes: payload,
+        offset: 0,
+    };
+    let strings = (0..decoder.count()?)
+        .map(|_| decoder.string())
+        .collect::<Result<Vec<Rc<str>>, String>>()?;
+    let string = |index: u32| {
+        strings
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| format!("string index {} out of range", index))
+    };
+    let globals = (0..decoder.count()?)
+        .map(|_| string(decoder.u32()?))
+        .collect::<Result<Vec<Rc<str>>, String>>()?;
+
+    let mut functions: Vec<Rc<Function>> = Vec::new();
+    for _ in 0..decoder.count()? {
+        let name = string(decoder.u32()?)?;
+        let arity = decoder.u8()?;
+        let upvalue_count = decoder.u8()?;
+        let code_len = decoder.count()?;
+        let code = decoder.take(code_len)?.to_vec();
+        let mut tokens = Vec::new();
+        for _ in 0..decoder.count()? {
+            let offset = decoder.u32()? as usize;
+            let type_name = string(decoder.u32()?)?;
+            let token_type = TokenType::from_name(&type_name)
+                .ok_or_else(|| format!("unknown token type '{}'", type_name))?;
+            let lexeme = string(decoder.u32()?)?;
+            let span = Span {
+                start: decoder.u32()? as usize,
+                end: decoder.u32()? as usize,
+                line: decoder.u32()?,
+                column: decoder.u32()?,
+            };
+            tokens.push((offset, Token::new(token_type, &lexeme, RoxType::Nil, span)));
+        }
+        let mut constants = Vec::new();
+        for _ in 0..decoder.count()? {
+            constants.push(match decoder.u8()? {
+                TAG_NUMBER => Constant::Number(f64::from_bits(decoder.u64()?)),
+                TAG_STRING => Constant::String(string(decoder.u32()?)?),
+                TAG_FUNCTION => {
+                    let index = decoder.u32()?;
+                    match functions.get(index as usize) {
+                        Some(function) => Constant::Function(function.clone()),
+                        None => return Err(format!("function index {} out of range", index)),
+                    }
+                }
+                tag => return Err(format!("unknown constant tag {}", tag)),
+            });
+        }
+        let function = Function {
+            name,
+            arity,
+            upvalue_count,
+            chunk: Chunk {
+                code,
+                constants,
+                tokens,
+            },
+        };
+        verify(&function, globals.len()).map_err(|message| {
+            let name = if function.name.is_empty() { "<script>" } else { &function.name };
+            format!("invalid bytecode in {}: {}", name, message)
+        })?;
+        functions.push(Rc::new(function));
+    }
+    if decoder.offset != payload.len() {
+        return Err(String::from("unexpected bytes after the functions"));
+    }
+    let script = functions.pop().ok_or("no script")?;
+    Ok(Program { script, globals })
+}
+
+#[derive(Default)]
+struct Encoder {
+    strings: Vec<Rc<str>>,
+    string_ids: HashMap<Rc<str>, u32>,
+    functions: Vec<u8>,
+    function_count: u32,
+}
+
+impl Encoder {
+    fn string(&mut self, string: &Rc<str>) -> u32 {
+        if let Some(&id) = self.string_ids.get(string) {
+            return id;
+        }
+        let id = self.strings.len() as u32;
+        self.strings.push(string.clone());
+        self.string_ids.insert(string.clone(), id);
+        id
+    }
+
+    // Writes the function after the ones it contains and returns its index
+    fn function(&mut self, function: &Function) -> u32 {
+        let chunk = &function.chunk;
+        let constants = chunk
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Number(n) => (TAG_NUMBER, n.to_bits()),
+                Constant::String(s) => (TAG_STRING, self.string(s) as u64),
+                Constant::Function(f) => (TAG_FUNCTION, self.function(f) as u64),
+            })
+            .collect::<Vec<(u8, u64)>>();
+        let name = self.string(&function.name);
+        let tokens = chunk
+            .tokens
+            .iter()
+            .map(|(offset, token)| {
+                let token_type = self.string(&Rc::from(format!("{:?}", token.token_type)));
+                let lexeme = self.string(&Rc::from(token.lexeme.as_str()));
+                (*offset, token_type, lexeme, token.span)
+            })
+            .collect::<Vec<(usize, u32, u32, Span)>>();
+
+        let out = &mut self.functions;
+        put_u32(out, name);
+        out.push(function.arity);
+        out.push(function.upvalue_count);
+        put_u32(out, chunk.code.len() as u32);
+        out.extend_from_slice(&chunk.code);
+        put_u32(out, tokens.len() as u32);
+        for (offset, token_type, lexeme, span) in tokens {
+            put_u32(out, offset as u32);
+            put_u32(out, token_type);
+            put_u32(out, lexeme);
+            for value in [span.start as u32, span.end as u32, span.line, span.column] {
+                put_u32(out, value);
+            }
+        }
+        put_u32(out, constants.len() as u32);
+        for (tag, value) in constants {
+            out.push(tag);
+            match tag {
+                TAG_NUMBER => out.extend_from_slice(&value.to_le_bytes()),
+                _ => put_u32(out, value as u32),
+            }
+        }
+
+        self.function_count += 1;
+        self.function_count - 1
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or("unexpected end of the program")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // A length, which can't be more than the bytes that are left
+    fn count(&mut self) -> Result<usize, String> {
+        let count = self.u32()? as usize;
+        if count > self.bytes.len() - self.offset {
+            return Err(String::from("unexpected end of the program"));
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self) -> Result<Rc<str>, String> {
+        let len = self.count()?;
+        std::str::from_utf8(self.take(len)?)
+            .map(Rc::from)
+            .map_err(|_| String::from("string is not valid UTF-8"))
+    }
+}
+
+// Checks every instruction is whole and its operands are in range, so the
+// VM can't index out of bounds on a damaged file. Stack slots aren't
+// checked; the compiler is trusted for those.
+fn verify(function: &Function, globals: usize) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let mut starts = Vec::new();
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        starts.push(offset);
+        let op = OpCode::from_byte(code[offset])
+            .ok_or_else(|| format!("unknown opcode {} at {}", code[offset], offset))?;
+        let operands = match op {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => 1,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::Closure => 2,
+            _ => 0,
+        };
+        if offset + operands >= code.len() {
+            return Err(format!("instruction at {} is cut off", offset));
+        }
+        let byte = code.get(offset + 1).copied().unwrap_or(0) as usize;
+        let wide = if operands == 2 { chunk.read_u16(offset + 1) as usize } else { 0 };
+        let constant = chunk.constants.get(wide);
+        let next = offset + 1 + operands;
+        match op {
+            OpCode::GetUpvalue | OpCode::SetUpvalue if byte >= function.upvalue_count as usize => {
+                return Err(format!("upvalue {} out of range at {}", byte, offset))
+            }
+            OpCode::Constant if !matches!(constant, Some(Constant::Number(_) | Constant::String(_))) => {
+                return Err(format!("bad constant {} at {}", wide, offset))
+            }
+            OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+                if !matches!(constant, Some(Constant::String(_))) =>
+            {
+                return Err(format!("bad name constant {} at {}", wide, offset))
+            }
+            OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal if wide >= globals => {
+                return Err(format!("global {} out of range at {}", wide, offset))
+            }
+            OpCode::Jump | OpCode::JumpIfFalse => targets.push(next + wide),
+            OpCode::Loop => match next.checked_sub(wide) {
+                Some(target) => targets.push(target),
+                None => return Err(format!("loop before the start of the code at {}", offset)),
+            },
+            OpCode::Closure => {
+                let Some(Constant::Function(inner)) = constant else {
+                    return Err(format!("bad function constant {} at {}", wide, offset));
+                };
+                let end = next + 2 * inner.upvalue_count as usize;
+                let Some(captures) = code.get(next..end) else {
+                    return Err(format!("instruction at {} is cut off", offset));
+                };
+                for capture in captures.chunks(2) {
+                    let (is_local, index) = (capture[0], capture[1]);
+                    if is_local > 1 || (is_local == 0 && index >= function.upvalue_count) {
+                        return Err(format!("bad upvalue capture at {}", offset));
+                    }
+                }
+                offset = end;
+                continue;
+            }
+            _ => (),
+        }
+        offset = next;
+    }
+
+    if starts.last().map(|&last| code[last]) != Some(OpCode::Return as u8) {
+        return Err(String::from("code doesn't end with a return"));
+    }
+    if let Some(target) = targets.iter().find(|target| starts.binary_search(target).is_err()) {
+        return Err(format!("jump to {}, which isn't an instruction", target));
+    }
+    if !chunk.tokens.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+        return Err(String::from("token table is out of order"));
+    }
+    verify_stack(function, &starts)
+}
+
+// Follows every path through the code tracking how many values the frame
+// has on the stack, which the compiler keeps the same wherever paths meet.
+// Every local slot, pop and capture must then be within the frame.
+fn verify_stack(function: &Function, starts: &[usize]) -> Result<(), String> {
+    let code = &function.chunk.code;
+    let mut depths = vec![None; starts.len()];
+    // The callee or receiver, then the arguments
+    let mut work = vec![(0, function.arity as usize + 1)];
+    while let Some((index, depth)) = work.pop() {
+        let offset = starts[index];
+        match depths[index] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(format!("stack depth differs between paths at {}", offset)),
+            None => depths[index] = Some(depth),
+        }
+        let op = OpCode::from_byte(code[offset]).ok_or("unknown opcode")?;
+        let byte = code.get(offset + 1).copied().unwrap_or(0) as usize;
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Class => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::Dup => (1, 2),
+            OpCode::Swap => (2, 2),
+            OpCode::Over => (2, 3),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Call => (byte + 1, 1),
+            _ => (2, 1),
+        };
+        if depth < pops {
+            return Err(format!("stack underflow at {}", offset));
+        }
+        let in_frame = match op {
+            OpCode::GetLocal | OpCode::SetLocal => byte < depth,
+            OpCode::Closure => code[offset + 3..starts.get(index + 1).copied().unwrap_or(code.len())]
+                .chunks(2)
+                .all(|capture| capture[0] == 0 || (capture[1] as usize) < depth),
+            _ => true,
+        };
+        if !in_frame {
+            return Err(format!("local slot out of range at {}", offset));
+        }
+
+        let depth = depth - pops + pushes;
+        let jump = || {
+            let distance = function.chunk.read_u16(offset + 1) as usize;
+            let target = match op {
+                OpCode::Loop => offset + 3 - distance,
+                _ => offset + 3 + distance,
+            };
+            starts.binary_search(&target).map_err(|_| format!("bad jump at {}", offset))
+        };
+        match op {
+            OpCode::Return => (),
+            OpCode::Jump | OpCode::Loop => work.push((jump()?, depth)),
+            OpCode::JumpIfFalse => {
+                work.push((jump()?, depth));
+                work.push((index + 1, depth));
+            }
+            _ => work.push((index + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// CRC-32 as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::disassemble;
+    use crate::Rox;
+
+    const SOURCE: &str = "
+var greeting = \"hi\";
+fun counter() {
+    var n = 0;
+    fun next() { n = n + 1; return n; }
+    return next;
+}
+class A { init(x) { this.x = x; } get() { return this.x; } }
+var c = counter();
+for (var i = 0; i < 3; i = i + 1) c();
+print greeting + \" \" + A(c()).get();
+";
+
+    fn compiled(source: &str) -> Program {
+        Rox::compile_bytecode(source).expect("compiles")
+    }
+
+    // Recomputes the checksum after the payload has been edited
+    fn reseal(bytes: &mut [u8]) {
+        let checksum = crc32(&bytes[HEADER_LEN..]);
+        bytes[12..16].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    fn rejects(bytes: &[u8], message: &str) {
+        match decode(bytes) {
+            Ok(_) => panic!("decoded a damaged program"),
+            Err(error) => assert!(error.contains(message), "{:?} lacks {:?}", error, message),
+        }
+    }
+
+    // Encodes the script of `print 1;` after changing its code
+    fn patched(patch: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut program = compiled("print 1;");
+        let script = Rc::get_mut(&mut program.script).unwrap();
+        patch(&mut script.chunk.code);
+        encode(&program)
+    }
+
+    #[test]
+    fn round_trips() {
+        let program = compiled(SOURCE);
+        let decoded = decode(&encode(&program)).expect("decodes");
+        assert_eq!(disassemble(&decoded), disassemble(&program));
+        assert_eq!(decoded.globals, program.globals);
+        assert_eq!(encode(&decoded), encode(&program));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = encode(&compiled(SOURCE));
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {} of {} bytes", len, bytes.len());
+        }
+        rejects(&bytes[..HEADER_LEN - 1], "truncated header");
+        rejects(&bytes[..bytes.len() - 1], "truncated");
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut bytes = encode(&compiled(SOURCE));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        rejects(&bytes, "checksum mismatch");
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = encode(&compiled(SOURCE));
+        bytes[4..6].copy_from_slice(&(ROXC_VERSION - 1).to_le_bytes());
+        rejects(&bytes, "unsupported format version 3");
+        bytes[4..6].copy_from_slice(&(ROXC_VERSION + 1).to_le_bytes());
+        rejects(&bytes, "unsupported format version 5");
+    }
+
+    #[test]
+    fn rejects_out_of_range_operands() {
+        let constant = patched(|code| {
+            assert_eq!(code[0], OpCode::Constant as u8);
+            code[1..3].copy_from_slice(&200u16.to_be_bytes());
+        });
+        rejects(&constant, "bad constant 200");
+
+        let global = patched(|code| {
+            code.splice(0..0, [OpCode::GetGlobal as u8, 0, 7, OpCode::Pop as u8]);
+        });
+        rejects(&global, "global 7 out of range");
+
+        let jump = patched(|code| {
+            code.splice(0..0, [OpCode::Jump as u8, 0, 1]);
+        });
+        rejects(&jump, "isn't an instruction");
+
+        let opcode = patched(|code| code[0] = 0xff);
+        rejects(&opcode, "unknown opcode 255");
+    }
+
+    #[test]
+    fn damaged_payloads_never_panic() {
+        let bytes = encode(&compiled(SOURCE));
+        for i in HEADER_LEN..bytes.len() {
+            for bit in [0x01, 0x80] {
+                let mut damaged = bytes.clone();
+                damaged[i] ^= bit;
+                reseal(&mut damaged);
+                let _ = decode(&damaged);
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{self, BufRead, Write};
+
+use crate::json::Json;
+
+// The base protocol shared by LSP and DAP: JSON bodies behind a
+// Content-Length header
+
+// Largest body accepted, well above any real document or request, so a
+// bad header can't make us allocate without bound
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+// Reads one message framed with a Content-Length header, or None at the end
+// of the input. Headers without a usable Content-Length are an error, since
+// there's no telling where the body ends.
+pub(crate) fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    let mut in_headers = false;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            if !in_headers {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input ended inside a header"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            // Blank lines between messages are skipped
+            if !in_headers {
+                continue;
+            }
+            break;
+        }
+        in_headers = true;
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim();
+                length = match value.parse::<usize>() {
+                    Ok(length) => Some(length),
+                    Err(_) => return Err(invalid(format!("invalid Content-Length '{}'", value))),
+                };
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid(String::from("missing Content-Length header")))?;
+    if length > MAX_MESSAGE_LEN {
+        return Err(invalid(format!(
+            "message of {} bytes is over the {} byte limit",
+            length, MAX_MESSAGE_LEN
+        )));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &str) -> io::Result<Option<String>> {
+        read_message(&mut input.as_bytes())
+    }
+
+    fn error(input: &str) -> io::Error {
+        read(input).expect_err("accepted a bad message")
+    }
+
+    #[test]
+    fn reads_framed_messages() {
+        let input = "Content-Length: 2\r\n\r\n{}\r\ncontent-length: 4\r\nContent-Type: x\r\n\r\nnull";
+        let mut input = input.as_bytes();
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("{}"));
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("null"));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_lengths() {
+        assert_eq!(error("Content-Type: x\r\n\r\n{}").kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error("Content-Length: -1\r\n\r\n{}").kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error("Content-Length: lots\r\n\r\n{}").kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_oversized_messages_before_reading_them() {
+        let header = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_LEN + 1);
+        assert_eq!(error(&header).kind(), io::ErrorKind::InvalidData);
+        let header = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+        assert_eq!(error(&header).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_cut_off_messages() {
+        assert_eq!(error("Content-Length: 10\r\n\r\n{}").kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error("Content-Length: 2\r\n").kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
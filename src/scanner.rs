@@ -1,6 +1,6 @@
-use crate::error;
+use crate::diagnostic::Diagnostic;
 use crate::rox_type::RoxType;
-use crate::token::Token;
+use crate::token::{Span, Token, TokenTrivia, Trivia, TriviaKind};
 
 use crate::token_type::TokenType;
 use std::collections::HashMap;
@@ -13,15 +13,29 @@ Remaining Tasks
 */
 
 pub struct Scanner {
+    text: String,
     source: Vec<char>,
+    // Byte offset of each char in source, plus one past the end
+    offsets: Vec<usize>,
     tokens: Vec<Token>,
+    errors: Vec<Diagnostic>,
     start: usize,
     current: usize,
     line: u32,
+    // Index in source of the first char on the current line
+    line_start: usize,
+    start_line: u32,
+    start_column: u32,
+    keep_trivia: bool,
+    // Trivia waiting for the next token
+    pending_trivia: Vec<Trivia>,
+    // Whether trivia still belongs to the last token's line
+    trailing: bool,
 }
 
 lazy_static! {
-    static ref IDENTIFIERS: HashMap<&'static str, TokenType> = HashMap::from([
+    // Keywords, by spelling
+    pub static ref IDENTIFIERS: HashMap<&'static str, TokenType> = HashMap::from([
         ("and", TokenType::And),
         ("class", TokenType::Class),
         ("else", TokenType::Else),
@@ -42,26 +56,52 @@ lazy_static! {
 }
 
 impl Scanner {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &str) -> Scanner {
+        let mut offsets = source.char_indices().map(|(i, _)| i).collect::<Vec<usize>>();
+        offsets.push(source.len());
         Scanner {
+            text: String::from(source),
             source: source.chars().collect::<Vec<char>>(),
+            offsets,
             tokens: Vec::new(),
+            errors: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            keep_trivia: false,
+            pending_trivia: Vec::new(),
+            trailing: false,
         }
     }
 
+    // Keeps comments, whitespace and unscannable text on the tokens so the
+    // source can be rebuilt exactly with token::to_source
+    pub fn with_trivia(source: &str) -> Scanner {
+        let mut scanner = Scanner::new(source);
+        scanner.keep_trivia = true;
+        scanner
+    }
+
     pub fn scan_tokens(&mut self) -> Vec<Token> {
         while !self.at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = (self.current - self.line_start) as u32 + 1;
             self.scan_token()
         }
 
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = (self.current - self.line_start) as u32 + 1;
+        let span = self.span();
         self.tokens
-            .push(Token::new(TokenType::EOF, "".to_string(), RoxType::Nil, 23));
+            .push(Token::new(TokenType::EOF, "", RoxType::Nil, span));
+        self.attach_pending_trivia();
 
-        return self.tokens[..].to_vec();
+        self.tokens[..].to_vec()
     }
 
     fn scan_token(&mut self) {
@@ -73,10 +113,32 @@ impl Scanner {
             '}' => self.add(TokenType::RightBrace),
             ',' => self.add(TokenType::Comma),
             '.' => self.add(TokenType::Dot),
-            '-' => self.add(TokenType::Minus),
-            '+' => self.add(TokenType::Plus),
             ';' => self.add(TokenType::Semicolon),
-            '*' => self.add(TokenType::Star),
+            '-' => {
+                if self.match_next('=') {
+                    self.add(TokenType::MinusEqual)
+                } else if self.match_next('-') {
+                    self.add(TokenType::MinusMinus)
+                } else {
+                    self.add(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.match_next('=') {
+                    self.add(TokenType::PlusEqual)
+                } else if self.match_next('+') {
+                    self.add(TokenType::PlusPlus)
+                } else {
+                    self.add(TokenType::Plus)
+                }
+            }
+            '*' => {
+                if self.match_next('=') {
+                    self.add(TokenType::StarEqual)
+                } else {
+                    self.add(TokenType::Star)
+                }
+            }
             '!' => {
                 if self.match_next('=') {
                     self.add(TokenType::BangEqual)
@@ -111,14 +173,23 @@ impl Scanner {
                     while self.peek() != '\n' && !self.at_end() {
                         self.advance();
                     }
+                    self.add_trivia(TriviaKind::Comment);
+                } else if self.match_next('=') {
+                    self.add(TokenType::SlashEqual)
                 } else {
                     self.add(TokenType::Slash)
                 }
             }
-            ' ' => (),
-            '\r' => (),
-            '\t' => (),
-            '\n' => self.line += 1,
+            ' ' | '\r' | '\t' => {
+                while matches!(self.peek(), ' ' | '\r' | '\t') {
+                    self.advance();
+                }
+                self.add_trivia(TriviaKind::Whitespace)
+            }
+            '\n' => {
+                self.newline();
+                self.add_trivia(TriviaKind::Newline)
+            }
             '"' => self.scn_string(),
             '0'..='9' => self.scn_number(),
 
@@ -126,7 +197,8 @@ impl Scanner {
                 if is_alphanumeric(other) {
                     self.scn_identifier()
                 } else {
-                    error(self.line, "Unexpected character")
+                    self.error("Unexpected character.", "not valid in Lox");
+                    self.add_trivia(TriviaKind::Unknown)
                 }
             }
         }
@@ -141,8 +213,71 @@ impl Scanner {
         let text = self.source[self.start..self.current]
             .iter()
             .collect::<String>();
+        let span = self.span();
         self.tokens
-            .push(Token::new(token_type, text, literal, self.line))
+            .push(Token::new(token_type, &text, literal, span));
+        self.attach_pending_trivia();
+        self.trailing = true;
+    }
+
+    // Gives the trivia seen since the last line break to the newest token
+    fn attach_pending_trivia(&mut self) {
+        if !self.keep_trivia {
+            return;
+        }
+        if let Some(token) = self.tokens.last_mut() {
+            token.trivia = Some(Box::new(TokenTrivia {
+                leading: std::mem::take(&mut self.pending_trivia),
+                trailing: Vec::new(),
+            }));
+        }
+    }
+
+    fn add_trivia(&mut self, kind: TriviaKind) {
+        if !self.keep_trivia {
+            return;
+        }
+        let trivia = Trivia {
+            kind,
+            text: self.source[self.start..self.current].iter().collect(),
+            span: self.span(),
+        };
+        let ends_line = self.source[self.start..self.current].contains(&'\n');
+        match self.tokens.last_mut() {
+            Some(Token {
+                trivia: Some(token_trivia),
+                ..
+            }) if self.trailing && !ends_line => token_trivia.trailing.push(trivia),
+            _ => {
+                self.trailing = false;
+                self.pending_trivia.push(trivia)
+            }
+        }
+    }
+
+    // Span of the lexeme between start and current
+    fn span(&self) -> Span {
+        Span {
+            start: self.offsets[self.start],
+            end: self.offsets[self.current],
+            line: self.start_line,
+            column: self.start_column,
+        }
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    fn error(&mut self, message: &str, label: &str) {
+        let diagnostic = Diagnostic::new(&self.text, self.span(), "", message, label);
+        self.errors.push(diagnostic)
+    }
+
+    // Errors found by the last scan; scanning carries on past them
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
     }
 
     fn scn_identifier(&mut self) {
@@ -163,14 +298,14 @@ impl Scanner {
 
     fn scn_string(&mut self) {
         while self.peek() != '"' && !self.at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.newline();
             }
-            self.advance();
         }
 
         if self.at_end() {
-            error(self.line, "unterminated string");
+            self.error("Unterminated string.", "string starts here");
+            self.add_trivia(TriviaKind::Unknown);
             return;
         }
 
@@ -226,32 +361,79 @@ impl Scanner {
 
     fn peek(&self) -> char {
         if self.at_end() {
-            return '\0';
+            '\0'
         } else {
-            return self.source[self.current];
+            self.source[self.current]
         }
     }
     fn peek_next(&self) -> char {
         if self.current + 1 >= self.source.len() {
-            return '\0';
+            '\0'
         } else {
-            return self.source[self.current + 1];
+            self.source[self.current + 1]
         }
     }
 
     fn at_end(&self) -> bool {
-        return self.current >= self.source.len();
+        self.current >= self.source.len()
     }
 }
 
 fn is_alphanumeric(ch: char) -> bool {
-    return is_alpha(ch) || is_digit(ch);
+    is_alpha(ch) || is_digit(ch)
 }
 
 fn is_alpha(ch: char) -> bool {
-    return ('a' <= ch && ch <= 'z') || ('A' <= ch && ch <= 'z') || (ch == '_');
+    ch.is_ascii_lowercase() || ch.is_ascii_uppercase() || (ch == '_')
 }
 
 fn is_digit(ch: char) -> bool {
-    return '0' <= ch && ch <= '9';
+    ch.is_ascii_digit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{to_source, TriviaKind};
+
+    fn round_trip(source: &str) {
+        let tokens = Scanner::with_trivia(source).scan_tokens();
+        assert_eq!(to_source(&tokens), source);
+    }
+
+    #[test]
+    fn rebuilds_source_exactly() {
+        round_trip("");
+        round_trip("print 1;");
+        round_trip("// only a comment");
+        round_trip("  \n\n\t// leading\nvar a = 1;   // trailing  \n\n\nprint a;\n");
+        round_trip("fun f(a,b){return a+b;}\r\nprint f( 1 ,\t2 );\r\n");
+        round_trip("var s = \"multi\nline // not a comment\";\n// last\n   ");
+        round_trip("print \"ünïcödé\"; // ✓\n");
+        round_trip("print \"unterminated\n");
+    }
+
+    #[test]
+    fn keeps_unscannable_text() {
+        let source = "var a = 1 @ 2; # $\nprint a;";
+        let tokens = Scanner::with_trivia(source).scan_tokens();
+        assert_eq!(to_source(&tokens), source);
+        let unknown = tokens
+            .iter()
+            .flat_map(|token| token.leading_trivia().iter().chain(token.trailing_trivia()))
+            .filter(|trivia| trivia.kind == TriviaKind::Unknown)
+            .count();
+        assert_eq!(unknown, 3);
+    }
+
+    #[test]
+    fn comments_belong_to_the_right_tokens() {
+        let tokens = Scanner::with_trivia("// above\nprint 1; // after\n").scan_tokens();
+        assert_eq!(tokens[0].leading_trivia()[0].text, "// above");
+        let semicolon = &tokens[2];
+        assert!(semicolon
+            .trailing_trivia()
+            .iter()
+            .any(|trivia| trivia.kind == TriviaKind::Comment && trivia.text == "// after"));
+    }
 }
@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+// An interned lexeme. Equal text always gets the same symbol, so comparing
+// and hashing symbols is comparing and hashing a u32. Each thread has its
+// own table, which is never emptied: the text of every symbol lives as long
+// as the program. Only names are interned, so the table stays as small as the
+// set of names a program uses. A symbol means nothing to another thread's
+// table, so it can't be sent to one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32, PhantomData<*const ()>);
+
+// Names the interpreter looks up itself, interned up front in this order
+const PREDEFINED: [&str; 3] = ["this", "super", "init"];
+
+impl Symbol {
+    pub const THIS: Symbol = Symbol(0, PhantomData);
+    pub const SUPER: Symbol = Symbol(1, PhantomData);
+    pub const INIT: Symbol = Symbol(2, PhantomData);
+
+    pub fn intern(text: &str) -> Symbol {
+        SYMBOLS.with(|symbols| symbols.borrow_mut().intern(text))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        SYMBOLS.with(|symbols| symbols.borrow().texts[self.0 as usize])
+    }
+}
+
+struct Interner {
+    texts: Vec<&'static str>,
+    symbols: HashMap<&'static str, Symbol>,
+}
+
+impl Interner {
+    fn new() -> Interner {
+        let mut interner = Interner {
+            texts: Vec::new(),
+            symbols: HashMap::new(),
+        };
+        for text in PREDEFINED {
+            interner.intern(text);
+        }
+        interner
+    }
+
+    fn intern(&mut self, text: &str) -> Symbol {
+        if let Some(&symbol) = self.symbols.get(text) {
+            return symbol;
+        }
+        let text: &'static str = Box::leak(Box::from(text));
+        let symbol = Symbol(self.texts.len() as u32, PhantomData);
+        self.texts.push(text);
+        self.symbols.insert(text, symbol);
+        symbol
+    }
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<Interner> = RefCell::new(Interner::new());
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+// A map keyed by symbols. They're small distinct integers already, so they
+// only need spreading out, not the default hasher's work.
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (self.0 ^ n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,291 @@
+use std::env;
+use std::fs::read_to_string;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{EX_DATAERR, EX_SOFTWARE};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a test script says it should do, from the comments the Crafting
+// Interpreters suite uses
+#[derive(Default)]
+struct Expectations {
+    // Lines of stdout, in order
+    output: Vec<String>,
+    // Compile errors as "[line N] Error ...", in any order
+    errors: Vec<String>,
+    // Message and line of the expected runtime error
+    runtime_error: Option<(String, u32)>,
+}
+
+impl Expectations {
+    // None when the file says it isn't a test
+    fn parse(source: &str) -> Option<Expectations> {
+        let mut expectations = Expectations::default();
+        for (i, line) in source.lines().enumerate() {
+            let number = i as u32 + 1;
+            if line.contains("// nontest") {
+                return None;
+            }
+            // Markers are searched for, as "// " alone may be inside a string
+            if let Some(output) = after(line, "// expect: ") {
+                expectations.output.push(String::from(output));
+            } else if let Some(message) = after(line, "// expect runtime error: ") {
+                expectations.runtime_error = Some((String::from(message), number));
+            } else if let Some(error) = after(line, "// Error") {
+                expectations.errors.push(format!("[line {}] Error{}", number, error));
+            } else if let Some(error) = line_error(line) {
+                expectations.errors.push(error);
+            }
+        }
+        Some(expectations)
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.errors.is_empty() {
+            EX_DATAERR
+        } else if self.runtime_error.is_some() {
+            EX_SOFTWARE
+        } else {
+            0
+        }
+    }
+}
+
+// The rest of the line after the first occurrence of marker
+fn after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|start| &line[start + marker.len()..])
+}
+
+// "// [line N] Error ..." and "// [java line N] Error ...". Errors only the
+// C implementation reports are left out.
+fn line_error(line: &str) -> Option<String> {
+    let rest = after(line, "// [line ").or_else(|| after(line, "// [java line "))?;
+    let (number, error) = rest.split_once("] ")?;
+    number.parse::<u32>().ok()?;
+    error
+        .starts_with("Error")
+        .then(|| format!("[line {}] {}", number, error))
+}
+
+struct Outcome {
+    stdout: String,
+    stderr: String,
+    exit_code: Option<i32>,
+}
+
+// Runs every test and prints the failures with what went wrong. Returns
+// the exit code. The options are passed on to rox run.
+pub fn run_tests(files: &[PathBuf], options: &[String]) -> i32 {
+    let rox = match env::current_exe() {
+        Ok(rox) => rox,
+        Err(error) => {
+            eprintln!("Could not find the rox executable: {}", error);
+            return 1;
+        }
+    };
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for file in files {
+        let source = match read_to_string(file) {
+            Ok(source) => source,
+            Err(error) => {
+                println!("FAIL {}\n    Could not read it: {}", file.display(), error);
+                failed += 1;
+                continue;
+            }
+        };
+        let Some(expectations) = Expectations::parse(&source) else {
+            skipped += 1;
+            continue;
+        };
+        let failures = match run(&rox, options, file) {
+            Ok(outcome) => check(&expectations, &outcome),
+            Err(error) => vec![format!("Could not run it: {}", error)],
+        };
+        if failures.is_empty() {
+            passed += 1;
+        } else {
+            failed += 1;
+            println!("FAIL {}", file.display());
+            for failure in failures {
+                for line in failure.lines() {
+                    println!("    {}", line);
+                }
+            }
+        }
+    }
+
+    let mut summary = format!("{} passed, {} failed", passed, failed);
+    if skipped > 0 {
+        summary.push_str(&format!(", {} skipped", skipped));
+    }
+    println!("{}", summary);
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+fn run(rox: &Path, options: &[String], file: &Path) -> io::Result<Outcome> {
+    let mut child = Command::new(rox)
+        .arg("run")
+        .args(options)
+        .arg(file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Read both pipes while waiting so a chatty script can't fill one up and
+    // block
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+    let exit_code = wait(&mut child)?;
+    Ok(Outcome {
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+        exit_code,
+    })
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut text);
+        }
+        text
+    })
+}
+
+// The exit code, or None if the script ran too long or was killed by a
+// signal
+fn wait(child: &mut Child) -> io::Result<Option<i32>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.code());
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// Everything about the run that doesn't match, empty if the test passed
+fn check(expectations: &Expectations, outcome: &Outcome) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    let expected = expectations
+        .output
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    let actual = outcome.stdout.lines().collect::<Vec<&str>>();
+    if expected != actual {
+        failures.push(format!("Output differs (-expected +actual):\n{}", diff(&expected, &actual)));
+    }
+
+    let stderr = outcome.stderr.lines().collect::<Vec<&str>>();
+    if let Some((message, line)) = &expectations.runtime_error {
+        // "[line N] Error at 'x': message" over the source line
+        let first = stderr.first().copied().unwrap_or_default();
+        let found = first.ends_with(&format!(": {}", message)) && error_line(first) == Some(*line);
+        if !found {
+            failures.push(format!(
+                "Expected runtime error '{}' on line {}, got:\n{}",
+                message,
+                line,
+                show(&outcome.stderr)
+            ));
+        }
+    } else {
+        // Diagnostics follow each "[line N] Error" header with the source
+        // line they point at, which isn't compared
+        let mut errors = stderr
+            .iter()
+            .filter(|line| error_line(line).is_some() && line.contains("] Error"))
+            .map(|line| line.to_string())
+            .collect::<Vec<String>>();
+        let mut expected = expectations.errors.clone();
+        errors.sort();
+        expected.sort();
+        if errors != expected {
+            let expected = expected.iter().map(String::as_str).collect::<Vec<&str>>();
+            let errors = errors.iter().map(String::as_str).collect::<Vec<&str>>();
+            failures.push(format!("Errors differ (-expected +actual):\n{}", diff(&expected, &errors)));
+        } else if expected.is_empty() && !outcome.stderr.is_empty() {
+            failures.push(format!("Unexpected output on stderr:\n{}", show(&outcome.stderr)));
+        }
+    }
+
+    match outcome.exit_code {
+        Some(code) if code == expectations.exit_code() => (),
+        Some(code) => failures.push(format!(
+            "Expected exit code {}, got {}",
+            expectations.exit_code(),
+            code
+        )),
+        None => failures.push(format!(
+            "Timed out after {}s or was killed",
+            TIMEOUT.as_secs()
+        )),
+    }
+    failures
+}
+
+// The N in a line starting "[line N]"
+fn error_line(line: &str) -> Option<u32> {
+    let rest = line.strip_prefix("[line ")?;
+    let end = rest.find(']')?;
+    rest[..end].parse().ok()
+}
+
+fn show(text: &str) -> String {
+    if text.trim().is_empty() {
+        String::from("(nothing)")
+    } else {
+        String::from(text.trim_matches('\n').trim_end())
+    }
+}
+
+// Line diff from the longest common subsequence, with unchanged lines
+// indented
+fn diff(expected: &[&str], actual: &[&str]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    // common[i][j] is the LCS length of expected[i..] and actual[j..]
+    let mut common = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
@@ -1,25 +1,158 @@
 use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
 use crate::rox_type::RoxType;
+use crate::symbol::Symbol;
 
 use crate::token_type::TokenType;
 
 #[derive(Clone, Debug)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
-    line: u32,
+    pub lexeme: Lexeme,
+    pub span: Span,
     pub literal: RoxType,
+    // Only filled in by Scanner::with_trivia, boxed so plain tokens stay small
+    pub trivia: Option<Box<TokenTrivia>>,
+}
+
+// A token's source text. Names, keywords and punctuation are interned, so
+// scopes can key on them; string and number literals keep their own text,
+// since a long-running session would otherwise intern every one it sees.
+#[derive(Clone, PartialEq)]
+pub enum Lexeme {
+    Name(Symbol),
+    Text(Rc<str>),
+}
+
+impl Lexeme {
+    // The interned name; only identifiers are ever looked up by it
+    pub fn symbol(&self) -> Symbol {
+        match self {
+            Lexeme::Name(symbol) => *symbol,
+            Lexeme::Text(text) => Symbol::intern(text),
+        }
+    }
+}
+
+impl Lexeme {
+    pub fn as_str(&self) -> &str {
+        self
+    }
+}
+
+impl Deref for Lexeme {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Lexeme::Name(symbol) => symbol.as_str(),
+            Lexeme::Text(text) => text,
+        }
+    }
+}
+
+impl PartialEq<Symbol> for Lexeme {
+    fn eq(&self, other: &Symbol) -> bool {
+        matches!(self, Lexeme::Name(symbol) if symbol == other)
+    }
+}
+
+impl PartialEq<&str> for Lexeme {
+    fn eq(&self, other: &&str) -> bool {
+        &**self == *other
+    }
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &**self)
+    }
+}
+
+impl fmt::Debug for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", &**self)
+    }
+}
+
+// Leading trivia is everything between the previous token's line and this
+// token; trailing trivia is whatever follows it on the same line, up to the
+// newline
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenTrivia {
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+
+// Source text the parser doesn't care about
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    Newline,
+    Comment,
+    // Text the scanner reported an error for
+    Unknown,
+}
+
+// Where a token sits in the source: a byte range plus the 1-based line and
+// column (in characters) of its first character
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, literal: RoxType, line: u32) -> Token {
+    pub fn new(token_type: TokenType, lexeme: &str, literal: RoxType, span: Span) -> Token {
+        let lexeme = match token_type {
+            TokenType::String | TokenType::Number => Lexeme::Text(Rc::from(lexeme)),
+            _ => Lexeme::Name(Symbol::intern(lexeme)),
+        };
         Token {
             token_type,
             lexeme,
-            line,
+            span,
             literal,
+            trivia: None,
         }
     }
+
+    pub fn leading_trivia(&self) -> &[Trivia] {
+        self.trivia.as_ref().map_or(&[], |trivia| &trivia.leading)
+    }
+
+    pub fn trailing_trivia(&self) -> &[Trivia] {
+        self.trivia.as_ref().map_or(&[], |trivia| &trivia.trailing)
+    }
+
+    // The token's text with its trivia, as it appeared in the source
+    pub fn full_text(&self) -> String {
+        let mut text = String::new();
+        for trivia in self.leading_trivia() {
+            text.push_str(&trivia.text);
+        }
+        text.push_str(&self.lexeme);
+        for trivia in self.trailing_trivia() {
+            text.push_str(&trivia.text);
+        }
+        text
+    }
+}
+
+// Rebuilds the source from a token stream scanned with trivia
+pub fn to_source(tokens: &[Token]) -> String {
+    tokens.iter().map(Token::full_text).collect()
 }
 
 // print out for tokens
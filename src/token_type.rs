@@ -1,3 +1,4 @@
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     // Single-character tokens.
@@ -22,6 +23,12 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    MinusEqual,
+    MinusMinus,
+    PlusEqual,
+    PlusPlus,
+    SlashEqual,
+    StarEqual,
 
     // Literals.
     Identifier,
@@ -48,3 +55,61 @@ pub enum TokenType {
 
     EOF,
 }
+
+impl TokenType {
+    // Looks a token type up by its variant name
+    pub fn from_name(name: &str) -> Option<TokenType> {
+        ALL_TOKEN_TYPES
+            .iter()
+            .find(|token_type| format!("{:?}", token_type) == name)
+            .cloned()
+    }
+}
+
+const ALL_TOKEN_TYPES: [TokenType; 45] = [
+    TokenType::LeftParen,
+    TokenType::RightParen,
+    TokenType::LeftBrace,
+    TokenType::RightBrace,
+    TokenType::Comma,
+    TokenType::Dot,
+    TokenType::Minus,
+    TokenType::Plus,
+    TokenType::Semicolon,
+    TokenType::Slash,
+    TokenType::Star,
+    TokenType::Bang,
+    TokenType::BangEqual,
+    TokenType::Equal,
+    TokenType::EqualEqual,
+    TokenType::Greater,
+    TokenType::GreaterEqual,
+    TokenType::Less,
+    TokenType::LessEqual,
+    TokenType::MinusEqual,
+    TokenType::MinusMinus,
+    TokenType::PlusEqual,
+    TokenType::PlusPlus,
+    TokenType::SlashEqual,
+    TokenType::StarEqual,
+    TokenType::Identifier,
+    TokenType::String,
+    TokenType::Number,
+    TokenType::And,
+    TokenType::Class,
+    TokenType::Else,
+    TokenType::False,
+    TokenType::Fun,
+    TokenType::For,
+    TokenType::If,
+    TokenType::Nil,
+    TokenType::Or,
+    TokenType::Print,
+    TokenType::Return,
+    TokenType::Super,
+    TokenType::This,
+    TokenType::True,
+    TokenType::Var,
+    TokenType::While,
+    TokenType::EOF,
+];
@@ -0,0 +1,604 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::callable::{Callable, NativeFunction};
+use crate::chunk::{Constant, Function, OpCode, Program};
+use crate::interpreter::{InterpreterError, StackFrame, MAX_CALL_DEPTH};
+use crate::natives;
+use crate::rox_type::RoxType;
+
+#[derive(Clone)]
+enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    Closure(Rc<Closure>),
+    Native(Rc<NativeFunction>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+}
+
+struct Closure {
+    function: Rc<Function>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+// A captured variable: a stack slot while its scope is live, then a value
+// of its own
+enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+struct Class {
+    name: Rc<str>,
+    // Filled in while the class statement runs; inherited methods are
+    // copied down
+    methods: RefCell<HashMap<Rc<str>, Rc<Closure>>>,
+}
+
+struct Instance {
+    class: Rc<Class>,
+    fields: RefCell<HashMap<Rc<str>, Value>>,
+}
+
+struct BoundMethod {
+    receiver: Value,
+    method: Rc<Closure>,
+}
+
+// A call in progress, saved while it calls another function
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Stack index of the callee, and of the frame's locals after it
+    base: usize,
+    // What to call the frame in a traceback, matching the tree-walker
+    name: Rc<str>,
+}
+
+// Runs compiled programs. Produces the same output and errors as the
+// tree-walking interpreter.
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Vec<Option<Value>>,
+    // Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    natives: HashMap<String, Rc<NativeFunction>>,
+    output: Box<dyn Write>,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        let mut vm = Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: Vec::new(),
+            open_upvalues: Vec::new(),
+            natives: HashMap::new(),
+            output: Box::new(io::stdout()),
+        };
+        vm.define_natives(natives::builtins());
+        vm
+    }
+
+    // Exposes command-line arguments to the script through argc() and argv(n)
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.define_natives(natives::arg_natives(args));
+    }
+
+    // Sends the output of print statements somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    fn define_natives(&mut self, natives: Vec<NativeFunction>) {
+        for native in natives {
+            self.natives.insert(native.name(), Rc::new(native));
+        }
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<(), InterpreterError> {
+        self.globals = program
+            .globals
+            .iter()
+            .map(|name| self.natives.get(&**name).map(|native| Value::Native(native.clone())))
+            .collect();
+        let script = Rc::new(Closure {
+            function: program.script.clone(),
+            upvalues: Vec::new(),
+        });
+        self.stack.push(Value::Closure(script.clone()));
+        let result = self.execute(script, &program.globals);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        result
+    }
+
+    fn execute(&mut self, script: Rc<Closure>, names: &[Rc<str>]) -> Result<(), InterpreterError> {
+        // The running frame lives in locals; callers are saved in frames
+        let mut closure = script;
+        let mut function = closure.function.clone();
+        let mut ip = 0;
+        let mut base = 0;
+        let mut name: Rc<str> = Rc::from("");
+
+        macro_rules! read_byte {
+            () => {{
+                ip += 1;
+                function.chunk.code[ip - 1]
+            }};
+        }
+        macro_rules! read_u16 {
+            () => {{
+                ip += 2;
+                function.chunk.read_u16(ip - 2)
+            }};
+        }
+        macro_rules! read_string {
+            () => {{
+                match &function.chunk.constants[read_u16!() as usize] {
+                    Constant::String(s) => s.clone(),
+                    _ => Rc::from(""),
+                }
+            }};
+        }
+        macro_rules! throw {
+            ($message:expr) => {
+                return Err(self.error($message, &function, ip, &name))
+            };
+        }
+        macro_rules! compare {
+            ($op:tt) => {{
+                let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) else {
+                    throw!("Operands must be numbers.");
+                };
+                self.stack.push(Value::Boolean(a $op b));
+            }};
+        }
+        macro_rules! arithmetic {
+            ($op:tt) => {{
+                let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) else {
+                    throw!("Operands must be numbers.");
+                };
+                self.stack.push(Value::Number(a $op b));
+            }};
+        }
+
+        loop {
+            let Some(op) = OpCode::from_byte(read_byte!()) else {
+                throw!("Invalid bytecode.");
+            };
+            match op {
+                OpCode::Constant => {
+                    let value = match &function.chunk.constants[read_u16!() as usize] {
+                        Constant::Number(n) => Value::Number(*n),
+                        Constant::String(s) => Value::String(s.clone()),
+                        Constant::Function(_) => Value::Nil,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::Dup => self.stack.push(self.peek(0).clone()),
+                OpCode::Swap => {
+                    let top = self.stack.len() - 1;
+                    self.stack.swap(top, top - 1);
+                }
+                OpCode::Over => self.stack.push(self.peek(1).clone()),
+                OpCode::GetLocal => {
+                    let slot = base + read_byte!() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = base + read_byte!() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal => {
+                    let index = read_u16!() as usize;
+                    match &self.globals[index] {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => throw!(&format!("Undefined variable '{}'.", names[index])),
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let index = read_u16!() as usize;
+                    self.globals[index] = Some(self.pop());
+                }
+                OpCode::SetGlobal => {
+                    let index = read_u16!() as usize;
+                    if self.globals[index].is_none() {
+                        throw!(&format!("Undefined variable '{}'.", names[index]));
+                    }
+                    self.globals[index] = Some(self.peek(0).clone());
+                }
+                OpCode::GetUpvalue => {
+                    let index = read_byte!() as usize;
+                    let value = match &*closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = read_byte!() as usize;
+                    let value = self.peek(0).clone();
+                    match &mut *closure.upvalues[index].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                // Fields shadow methods; methods come back bound to the instance
+                OpCode::GetProperty => {
+                    let property = read_string!();
+                    let Value::Instance(instance) = self.peek(0).clone() else {
+                        throw!("Only instances have properties.");
+                    };
+                    let field = instance.fields.borrow().get(&property).cloned();
+                    let value = match field {
+                        Some(value) => value,
+                        None => match instance.class.methods.borrow().get(&property) {
+                            Some(method) => Value::BoundMethod(Rc::new(BoundMethod {
+                                receiver: Value::Instance(instance.clone()),
+                                method: method.clone(),
+                            })),
+                            None => throw!(&format!("Undefined property '{}'.", property)),
+                        },
+                    };
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty => {
+                    let property = read_string!();
+                    let value = self.pop();
+                    let Value::Instance(instance) = self.pop() else {
+                        throw!("Only instances have fields.");
+                    };
+                    instance.fields.borrow_mut().insert(property, value.clone());
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let method = read_string!();
+                    let (Value::Class(superclass), receiver) = (self.pop(), self.pop()) else {
+                        throw!("Superclass must be a class.");
+                    };
+                    let bound = match superclass.methods.borrow().get(&method) {
+                        Some(closure) => BoundMethod {
+                            receiver,
+                            method: closure.clone(),
+                        },
+                        None => throw!(&format!("Undefined property '{}'.", method)),
+                    };
+                    self.stack.push(Value::BoundMethod(Rc::new(bound)));
+                }
+                OpCode::Equal => {
+                    let (b, a) = (self.pop(), self.pop());
+                    self.stack.push(Value::Boolean(values_equal(&a, &b)));
+                }
+                OpCode::NotEqual => {
+                    let (b, a) = (self.pop(), self.pop());
+                    self.stack.push(Value::Boolean(!values_equal(&a, &b)));
+                }
+                OpCode::Greater => compare!(>),
+                OpCode::GreaterEqual => compare!(>=),
+                OpCode::Less => compare!(<),
+                OpCode::LessEqual => compare!(<=),
+                OpCode::Add => match (self.pop(), self.pop()) {
+                    (Value::Number(b), Value::Number(a)) => self.stack.push(Value::Number(a + b)),
+                    (Value::String(b), Value::String(a)) => {
+                        let mut joined = String::with_capacity(a.len() + b.len());
+                        joined.push_str(&a);
+                        joined.push_str(&b);
+                        self.stack.push(Value::String(Rc::from(joined)));
+                    }
+                    _ => throw!("Operands must be two numbers or two strings."),
+                },
+                OpCode::Subtract => arithmetic!(-),
+                OpCode::Multiply => arithmetic!(*),
+                OpCode::Divide => arithmetic!(/),
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!truthy(&value)));
+                }
+                OpCode::Negate => {
+                    let Value::Number(n) = self.pop() else {
+                        throw!("Operand must be a number.");
+                    };
+                    self.stack.push(Value::Number(-n));
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    if writeln!(self.output, "{}", value).is_err() {
+                        throw!("Could not write output.");
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = read_u16!() as usize;
+                    ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = read_u16!() as usize;
+                    if !truthy(self.peek(0)) {
+                        ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = read_u16!() as usize;
+                    ip -= offset;
+                }
+                OpCode::Call => {
+                    let argc = read_byte!() as usize;
+                    let callee_slot = self.stack.len() - argc - 1;
+                    let (callee, callee_name) = match self.stack[callee_slot].clone() {
+                        Value::Closure(callee) => {
+                            let callee_name = callee.function.name.clone();
+                            (callee, callee_name)
+                        }
+                        Value::BoundMethod(bound) => {
+                            self.stack[callee_slot] = bound.receiver.clone();
+                            (bound.method.clone(), bound.method.function.name.clone())
+                        }
+                        Value::Class(class) => {
+                            let instance = Instance {
+                                class: class.clone(),
+                                fields: RefCell::new(HashMap::new()),
+                            };
+                            self.stack[callee_slot] = Value::Instance(Rc::new(instance));
+                            let initializer = class.methods.borrow().get("init").cloned();
+                            match initializer {
+                                Some(initializer) => (initializer, class.name.clone()),
+                                None if argc == 0 => continue,
+                                None => throw!(&format!("Expected 0 arguments but got {}.", argc)),
+                            }
+                        }
+                        Value::Native(native) => {
+                            if argc != native.arity() {
+                                throw!(&format!(
+                                    "Expected {} arguments but got {}.",
+                                    native.arity(),
+                                    argc
+                                ));
+                            }
+                            let arguments = self.stack[callee_slot + 1..]
+                                .iter()
+                                .map(to_rox)
+                                .collect::<Vec<RoxType>>();
+                            let result = match native.call_native(&arguments) {
+                                Ok(result) => result,
+                                Err(e) => throw!(&e.error_string),
+                            };
+                            self.stack.truncate(callee_slot);
+                            self.stack.push(from_rox(result));
+                            continue;
+                        }
+                        _ => throw!("Can only call functions and classes."),
+                    };
+                    let arity = callee.function.arity as usize;
+                    if argc != arity {
+                        throw!(&format!("Expected {} arguments but got {}.", arity, argc));
+                    }
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        throw!("Stack overflow.");
+                    }
+
+                    let caller = std::mem::replace(&mut closure, callee);
+                    self.frames.push(CallFrame {
+                        closure: caller,
+                        ip,
+                        base,
+                        name: std::mem::replace(&mut name, callee_name),
+                    });
+                    function = closure.function.clone();
+                    ip = 0;
+                    base = callee_slot;
+                }
+                OpCode::Closure => {
+                    let Constant::Function(inner) = &function.chunk.constants[read_u16!() as usize]
+                    else {
+                        throw!("Invalid bytecode.");
+                    };
+                    let inner = inner.clone();
+                    let mut upvalues = Vec::with_capacity(inner.upvalue_count as usize);
+                    for _ in 0..inner.upvalue_count {
+                        let is_local = read_byte!() == 1;
+                        let index = read_byte!() as usize;
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(base + index)
+                        } else {
+                            closure.upvalues[index].clone()
+                        });
+                    }
+                    self.stack.push(Value::Closure(Rc::new(Closure {
+                        function: inner,
+                        upvalues,
+                    })));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(base);
+                    let Some(caller) = self.frames.pop() else {
+                        return Ok(());
+                    };
+                    self.stack.truncate(base);
+                    self.stack.push(result);
+                    closure = caller.closure;
+                    function = closure.function.clone();
+                    ip = caller.ip;
+                    base = caller.base;
+                    name = caller.name;
+                }
+                OpCode::Class => {
+                    let class = Class {
+                        name: read_string!(),
+                        methods: RefCell::new(HashMap::new()),
+                    };
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+                OpCode::Inherit => {
+                    let (Value::Class(superclass), Value::Class(subclass)) =
+                        (self.peek(1).clone(), self.peek(0).clone())
+                    else {
+                        throw!("Superclass must be a class.");
+                    };
+                    let inherited = superclass.methods.borrow().clone();
+                    subclass.methods.borrow_mut().extend(inherited);
+                    self.pop();
+                }
+                OpCode::Method => {
+                    let method_name = read_string!();
+                    let (Value::Closure(method), Value::Class(class)) = (self.pop(), self.peek(0))
+                    else {
+                        throw!("Invalid bytecode.");
+                    };
+                    class.methods.borrow_mut().insert(method_name, method);
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap_or(Value::Nil)
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // Shares one upvalue between every closure capturing the same slot
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open_slot = |upvalue: &Rc<RefCell<Upvalue>>| match &*upvalue.borrow() {
+            Upvalue::Open(slot) => *slot,
+            Upvalue::Closed(_) => 0,
+        };
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < slot);
+        if let Some(existing) = self.open_upvalues.get(position) {
+            if open_slot(existing) == slot {
+                return existing.clone();
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue.clone());
+        upvalue
+    }
+
+    // Moves the values of upvalues at or above a slot off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => break,
+            };
+            if slot < from {
+                break;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    // A runtime error at the token the failing instruction was compiled for,
+    // with a traceback, innermost call first, in the same form as the
+    // tree-walker's
+    fn error(&self, message: &str, function: &Function, ip: usize, name: &str) -> InterpreterError {
+        let mut error = match function.chunk.token_at(ip - 1) {
+            Some(token) => InterpreterError::at(token, message),
+            None => InterpreterError::new(message),
+        };
+        error.stack.push(stack_frame(name, function.chunk.line_at(ip - 1)));
+        for frame in self.frames.iter().rev() {
+            let line = frame.closure.function.chunk.line_at(frame.ip - 1);
+            error.stack.push(stack_frame(&frame.name, line));
+        }
+        error
+    }
+}
+
+fn stack_frame(name: &str, line: u32) -> StackFrame {
+    let function = if name.is_empty() {
+        String::from("script")
+    } else {
+        format!("{}()", name)
+    };
+    StackFrame { function, line }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Boolean(b) => *b,
+        Value::Nil => false,
+        _ => true,
+    }
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Boolean(a), Value::Boolean(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+        (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+        (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+        (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+        (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+// Natives exchange plain values; anything else reaches them as nil
+fn to_rox(value: &Value) -> RoxType {
+    match value {
+        Value::Boolean(b) => RoxType::Boolean(*b),
+        Value::Number(n) => RoxType::Number(*n),
+        Value::String(s) => RoxType::String(s.to_string()),
+        _ => RoxType::Nil,
+    }
+}
+
+fn from_rox(value: RoxType) -> Value {
+    match value {
+        RoxType::Boolean(b) => Value::Boolean(b),
+        RoxType::Number(n) => Value::Number(n),
+        RoxType::String(s) => Value::String(Rc::from(s)),
+        _ => Value::Nil,
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+            Value::Native(native) => write!(f, "{}", native),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::BoundMethod(bound) => write!(f, "<fn {}>", bound.method.function.name),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn rox_fmt(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rox"))
+        .arg("fmt")
+        .args(args)
+        .output()
+        .expect("rox runs")
+}
+
+// A scratch directory of its own for each test
+fn scratch(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn check_passes_formatted_files() {
+    let dir = scratch("fmt_formatted");
+    let file = dir.join("ok.lox");
+    fs::write(&file, "if (a)\n    if (b) print 1;\n    else print 2;\n").unwrap();
+    let output = rox_fmt(&["--check", file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn check_fails_without_rewriting() {
+    let dir = scratch("fmt_unformatted");
+    let file = dir.join("messy.lox");
+    let source = "if (a) if (b) print 1; else print 2;\n";
+    fs::write(&file, source).unwrap();
+    let output = rox_fmt(&["--check", dir.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("messy.lox"));
+    assert_eq!(fs::read_to_string(&file).unwrap(), source);
+
+    // Formatting for real makes the check pass
+    assert_eq!(rox_fmt(&[file.to_str().unwrap()]).status.code(), Some(0));
+    assert_eq!(rox_fmt(&["--check", file.to_str().unwrap()]).status.code(), Some(0));
+}
+
+#[test]
+fn check_reports_code_that_does_not_parse() {
+    let dir = scratch("fmt_broken");
+    let file = dir.join("broken.lox");
+    fs::write(&file, "print (1;\n").unwrap();
+    let output = rox_fmt(&["--check", file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+}
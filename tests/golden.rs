@@ -0,0 +1,32 @@
+use std::process::Command;
+
+// Runs the scripts in testfiles/golden through rox test, which checks them
+// against their // expect comments
+fn rox_test(options: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rox"))
+        .arg("test")
+        .args(options)
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/testfiles/golden"))
+        .output()
+        .expect("rox runs");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn tree_walker() {
+    rox_test(&[]);
+}
+
+#[test]
+fn vm() {
+    rox_test(&["--backend", "vm"]);
+}
+
+#[test]
+fn gc_stress() {
+    rox_test(&["--gc-stress"]);
+}
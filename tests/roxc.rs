@@ -0,0 +1,72 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn rox(args: &[&OsStr]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rox"))
+        .args(args)
+        .output()
+        .expect("rox runs")
+}
+
+fn compile(source: &Path, output: &Path) {
+    let result = rox(&["compile".as_ref(), source.as_ref(), "-o".as_ref(), output.as_ref()]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+const SCRIPT: &str = "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+class Counter {
+    init() { this.count = 0; }
+    add(n) { this.count += n; return this; }
+}
+var c = Counter();
+for (var i = 0; i < 10; i++) c.add(fib(i));
+print c.count;
+print \"done\";
+print nil + 1;
+";
+
+#[test]
+fn compiled_scripts_run_like_their_source() {
+    let dir = scratch("roxc_round_trip");
+    let source = dir.join("script.lox");
+    let compiled = dir.join("script.roxc");
+    fs::write(&source, SCRIPT).unwrap();
+
+    compile(&source, &compiled);
+    assert!(fs::read(&compiled).unwrap().starts_with(b"ROXC"));
+
+    let expected = rox(&[source.as_ref()]);
+    let actual = rox(&[compiled.as_ref()]);
+    assert_eq!(String::from_utf8_lossy(&actual.stdout), "88\ndone\n");
+    assert_eq!(actual.stdout, expected.stdout);
+    assert_eq!(actual.status.code(), Some(70));
+    // The line map survives, so the error still points at line 10
+    assert!(String::from_utf8_lossy(&actual.stderr).contains("[line 10]"));
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let dir = scratch("roxc_damaged");
+    let source = dir.join("script.lox");
+    let compiled = dir.join("script.roxc");
+    fs::write(&source, SCRIPT).unwrap();
+    compile(&source, &compiled);
+
+    let bytes = fs::read(&compiled).unwrap();
+    let damaged = dir.join("damaged.roxc");
+    for bytes in [&bytes[..bytes.len() / 2], &bytes[..20]] {
+        fs::write(&damaged, bytes).unwrap();
+        let output = rox(&[damaged.as_ref()]);
+        assert_eq!(output.status.code(), Some(65));
+        assert!(output.stdout.is_empty());
+    }
+}